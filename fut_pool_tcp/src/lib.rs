@@ -36,6 +36,8 @@ impl Deref for TcpConnection {
 }
 
 impl PoolObject for TcpConnection {
+    type Error = io::Error;
+
     fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool>> {
         let mut buf = [0, 1];
         match self.0.poll_peek(&mut buf) {
@@ -1,6 +1,6 @@
-pub use tokio_retry::strategy::{
-    jitter, ExponentialBackoff, FibonacciBackoff, FixedInterval as FixedIntervalBackoff,
-};
+use std::time::Duration;
+
+use rand::random;
 
 #[derive(Clone)]
 pub enum BackoffStrategy {
@@ -9,3 +9,135 @@ pub enum BackoffStrategy {
     Fixed(FixedIntervalBackoff),
     None,
 }
+
+impl BackoffStrategy {
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        match self {
+            BackoffStrategy::Exponential(ref mut bo) => bo.next(),
+            BackoffStrategy::Fibonacci(ref mut bo) => bo.next(),
+            BackoffStrategy::Fixed(ref mut bo) => bo.next(),
+            BackoffStrategy::None => None,
+        }
+    }
+}
+
+/// Scales `duration` by a random factor between 0 and 1.
+pub fn jitter(duration: Duration) -> Duration {
+    let jitter = random::<f64>();
+    let secs = ((duration.as_secs() as f64) * jitter).ceil() as u64;
+    let nanos = ((f64::from(duration.subsec_nanos())) * jitter).ceil() as u32;
+    Duration::new(secs, nanos)
+}
+
+fn cap(delay: Duration, max_delay: Option<Duration>) -> Duration {
+    match max_delay {
+        Some(max_delay) if delay > max_delay => max_delay,
+        _ => delay,
+    }
+}
+
+/// Delays of `base^n * factor` milliseconds.
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    current: u64,
+    base: u64,
+    factor: u64,
+    max_delay: Option<Duration>,
+}
+
+impl ExponentialBackoff {
+    pub fn from_millis(base: u64) -> ExponentialBackoff {
+        ExponentialBackoff {
+            current: base,
+            base,
+            factor: 1,
+            max_delay: None,
+        }
+    }
+
+    pub fn factor(mut self, factor: u64) -> ExponentialBackoff {
+        self.factor = factor;
+        self
+    }
+
+    pub fn max_delay(mut self, duration: Duration) -> ExponentialBackoff {
+        self.max_delay = Some(duration);
+        self
+    }
+}
+
+impl Iterator for ExponentialBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let millis = self.current.saturating_mul(self.factor);
+        self.current = self.current.saturating_mul(self.base);
+        Some(cap(Duration::from_millis(millis), self.max_delay))
+    }
+}
+
+/// Delays following the fibonacci sequence, starting at `millis * factor` milliseconds.
+#[derive(Debug, Clone)]
+pub struct FibonacciBackoff {
+    current: u64,
+    next: u64,
+    factor: u64,
+    max_delay: Option<Duration>,
+}
+
+impl FibonacciBackoff {
+    pub fn from_millis(millis: u64) -> FibonacciBackoff {
+        FibonacciBackoff {
+            current: millis,
+            next: millis,
+            factor: 1,
+            max_delay: None,
+        }
+    }
+
+    pub fn factor(mut self, factor: u64) -> FibonacciBackoff {
+        self.factor = factor;
+        self
+    }
+
+    pub fn max_delay(mut self, duration: Duration) -> FibonacciBackoff {
+        self.max_delay = Some(duration);
+        self
+    }
+}
+
+impl Iterator for FibonacciBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let millis = self.current.saturating_mul(self.factor);
+        let next = self.current.saturating_add(self.next);
+        self.current = self.next;
+        self.next = next;
+        Some(cap(Duration::from_millis(millis), self.max_delay))
+    }
+}
+
+/// The same delay every time.
+#[derive(Debug, Clone)]
+pub struct FixedIntervalBackoff {
+    duration: Duration,
+}
+
+impl FixedIntervalBackoff {
+    pub fn new(duration: Duration) -> FixedIntervalBackoff {
+        FixedIntervalBackoff { duration }
+    }
+
+    pub fn from_millis(millis: u64) -> FixedIntervalBackoff {
+        FixedIntervalBackoff::new(Duration::from_millis(millis))
+    }
+}
+
+impl Iterator for FixedIntervalBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        Some(self.duration)
+    }
+}
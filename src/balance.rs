@@ -0,0 +1,417 @@
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use parking_lot::Mutex;
+use rand::random;
+
+use crate::builder::PoolBuilder;
+use crate::error::{Error, Phase};
+use crate::events::{PoolEventListener, TakeProgress};
+use crate::guard::PoolGuard;
+use crate::object::PoolObject;
+use crate::pool::Pool;
+use crate::runtime::{Runtime, RuntimeFuture};
+use crate::taker::PoolTaker;
+
+/// How `BalancedPool::take` picks among the healthy endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Each endpoint in turn.
+    RoundRobin,
+    /// The endpoint with the fewest objects checked out or being created.
+    LeastInUse,
+    /// The less busy of two endpoints picked at random.
+    PowerOfTwoChoices,
+}
+
+/// Spreads takes across the pools of several endpoints, e.g. the replicas of a database.
+///
+/// An endpoint is marked unhealthy after `failure_threshold` factory calls or health checks
+/// fail in a row. Takes skip it while a probe take, spawned on its runtime every
+/// `probe_interval`, checks whether it recovered. A take which fails on one endpoint is
+/// retried on the others within the `timeout` of the whole take, objects go back to the
+/// pool of their endpoint.
+pub struct BalancedPool<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    inner: Arc<BalancedPoolInner<T>>,
+}
+
+struct BalancedPoolInner<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    endpoints: Vec<Endpoint<T>>,
+    strategy: BalanceStrategy,
+    timeout: Option<Duration>,
+    //the runtime of the first endpoint, it times the whole take and its failovers
+    runtime: Arc<dyn Runtime>,
+    next: AtomicUsize,
+}
+
+struct Endpoint<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pool: Pool<T>,
+    health: Arc<EndpointHealth>,
+}
+
+impl<T> Clone for BalancedPool<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        BalancedPool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> BalancedPool<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pub fn builder() -> BalancedPoolBuilder<T> {
+        BalancedPoolBuilder::new()
+    }
+
+    /// Takes an object from one of the endpoints. Every endpoint tried gets an even share
+    /// of the `timeout` left for the endpoints which weren't tried yet. Fails with the error
+    /// of the last endpoint tried once every endpoint failed, which is `Error::Timeout`
+    /// in the phase it was in if its share ran out.
+    pub async fn take(&self) -> Result<PoolGuard<T>, Error<T::Error>> {
+        self.spawn_probes();
+
+        let deadline = self.inner.timeout.map(|timeout| self.inner.runtime.now() + timeout);
+        let mut tried = vec![false; self.inner.endpoints.len()];
+        let mut last_error = Error::Timeout {
+            phase: Phase::Waiting,
+        };
+        while let Some(index) = self.pick(&tried) {
+            let left = tried.iter().filter(|tried| !**tried).count();
+            tried[index] = true;
+            let share = match deadline {
+                Some(deadline) => {
+                    let now = self.inner.runtime.now();
+                    if now >= deadline {
+                        break;
+                    }
+                    Some((deadline - now) / left as u32)
+                }
+                None => None,
+            };
+
+            let endpoint = &self.inner.endpoints[index];
+            let mut taker = PoolTaker::new(endpoint.pool.clone());
+            let res = match share {
+                Some(share) => {
+                    let attempt: RuntimeFuture = self.inner.runtime.sleep(share);
+                    match future::select(&mut taker, attempt).await {
+                        Either::Left((res, _)) => res,
+                        Either::Right(_) => {
+                            taker.timed_out();
+                            let phase = taker.phase();
+                            debug!("balanced take timed out on endpoint {} while {}", index, phase);
+                            //the pool of the endpoint didn't time out yet, count the hang here
+                            if phase == Phase::Connecting {
+                                endpoint.health.failed();
+                            }
+                            Err(Error::Timeout { phase })
+                        }
+                    }
+                }
+                None => (&mut taker).await,
+            };
+
+            match res {
+                Ok(object) => return Ok(object),
+                Err(Error::Closed) => return Err(Error::Closed),
+                Err(err) => {
+                    debug!("endpoint {} failed, err={}", index, &err);
+                    last_error = err;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// The pool of every endpoint, in the order they were added.
+    pub fn endpoints(&self) -> Vec<Pool<T>> {
+        self.inner
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.pool.clone())
+            .collect()
+    }
+
+    /// Whether the endpoint at `index` gets regular traffic.
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.inner.endpoints[index].health.is_healthy()
+    }
+
+    pub fn close(&self) {
+        self.inner
+            .endpoints
+            .iter()
+            .for_each(|endpoint| endpoint.pool.close());
+    }
+
+    /// Spawns a take on every unhealthy endpoint whose probe is due, the object goes
+    /// straight back to its pool. It marks the endpoint healthy again once it succeeds.
+    fn spawn_probes(&self) {
+        for (index, endpoint) in self.inner.endpoints.iter().enumerate() {
+            if !endpoint.health.probe_due() {
+                continue;
+            }
+
+            debug!("probing unhealthy endpoint {}", index);
+            let pool = endpoint.pool.clone();
+            endpoint.pool.inner.runtime.spawn(Box::pin(async move {
+                if let Err(err) = pool.take().await {
+                    debug!("probe failed, err={}", &err);
+                }
+            }));
+        }
+    }
+
+    /// Picks the next endpoint among the ones which weren't tried yet.
+    fn pick(&self, tried: &[bool]) -> Option<usize> {
+        let untried: Vec<usize> = (0..tried.len()).filter(|index| !tried[*index]).collect();
+        if untried.is_empty() {
+            return None;
+        }
+
+        let endpoints = &self.inner.endpoints;
+        let healthy: Vec<usize> = untried
+            .iter()
+            .cloned()
+            .filter(|index| endpoints[*index].health.is_healthy())
+            .collect();
+        //every endpoint left is unhealthy, trying them beats failing right away
+        let candidates = if healthy.is_empty() { untried } else { healthy };
+        Some(self.choose(&candidates))
+    }
+
+    fn choose(&self, candidates: &[usize]) -> usize {
+        match self.inner.strategy {
+            BalanceStrategy::RoundRobin => {
+                let next = self.inner.next.fetch_add(1, Ordering::SeqCst);
+                candidates[next % candidates.len()]
+            }
+            BalanceStrategy::LeastInUse => *candidates
+                .iter()
+                .min_by_key(|index| self.load(**index))
+                .unwrap(),
+            BalanceStrategy::PowerOfTwoChoices => {
+                if candidates.len() == 1 {
+                    return candidates[0];
+                }
+
+                let first = random::<usize>() % candidates.len();
+                let mut second = random::<usize>() % (candidates.len() - 1);
+                if second >= first {
+                    second += 1;
+                }
+
+                let (first, second) = (candidates[first], candidates[second]);
+                if self.load(second) < self.load(first) {
+                    second
+                } else {
+                    first
+                }
+            }
+        }
+    }
+
+    fn load(&self, index: usize) -> usize {
+        let state = self.inner.endpoints[index].pool.state();
+        state.in_use + state.pending + state.waiters
+    }
+}
+
+pub struct BalancedPoolBuilder<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    _endpoints: Vec<PoolBuilder<T>>,
+    _strategy: BalanceStrategy,
+    _failure_threshold: usize,
+    _probe_interval: Duration,
+    _timeout: Option<Duration>,
+}
+
+impl<T> BalancedPoolBuilder<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pub fn new() -> BalancedPoolBuilder<T> {
+        BalancedPoolBuilder {
+            _endpoints: Vec::new(),
+            _strategy: BalanceStrategy::RoundRobin,
+            _failure_threshold: 3,
+            _probe_interval: Duration::from_secs(5),
+            _timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Adds an endpoint, the builder configures its pool like any other.
+    pub fn endpoint(mut self, builder: PoolBuilder<T>) -> Self {
+        self._endpoints.push(builder);
+        self
+    }
+
+    /// How takes are spread, `BalanceStrategy::RoundRobin` by default.
+    pub fn strategy(mut self, strategy: BalanceStrategy) -> Self {
+        self._strategy = strategy;
+        self
+    }
+
+    /// How many factory calls or health checks of an endpoint fail in a row
+    /// before it's marked unhealthy.
+    pub fn failure_threshold(mut self, threshold: usize) -> Self {
+        self._failure_threshold = threshold.max(1);
+        self
+    }
+
+    /// How often an unhealthy endpoint gets a take to check whether it recovered.
+    pub fn probe_interval(mut self, interval: Duration) -> Self {
+        self._probe_interval = interval;
+        self
+    }
+
+    /// The deadline of a whole take, failovers included, 10s by default. Every endpoint
+    /// tried gets an even share of the time left, so that a hanging endpoint leaves time
+    /// for the others even if its own pool `timeout` is longer.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self._timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> BalancedPool<T> {
+        assert!(!self._endpoints.is_empty(), "A pool endpoint is required");
+
+        let threshold = self._failure_threshold;
+        let probe_interval = self._probe_interval;
+        let endpoints: Vec<Endpoint<T>> = self
+            ._endpoints
+            .into_iter()
+            .map(|mut builder| {
+                let runtime = builder.shared_runtime();
+                let health = Arc::new(EndpointHealth::new(threshold, probe_interval, runtime));
+                Endpoint {
+                    pool: builder.add_event_listener(health.clone()).build(),
+                    health,
+                }
+            })
+            .collect();
+
+        BalancedPool {
+            inner: Arc::new(BalancedPoolInner {
+                runtime: endpoints[0].pool.inner.runtime.clone(),
+                endpoints,
+                strategy: self._strategy,
+                timeout: self._timeout,
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+/// Follows the failures of an endpoint through the events of its pool.
+struct EndpointHealth {
+    threshold: usize,
+    probe_interval: Duration,
+    //the clock of the endpoint's pool
+    runtime: Arc<dyn Runtime>,
+    //factory calls and health checks which failed in a row
+    failures: AtomicUsize,
+    unhealthy: AtomicBool,
+    //when an unhealthy endpoint gets its next probe, armed when it's marked unhealthy
+    next_probe: Mutex<Option<Instant>>,
+}
+
+impl EndpointHealth {
+    fn new(
+        threshold: usize,
+        probe_interval: Duration,
+        runtime: Arc<dyn Runtime>,
+    ) -> EndpointHealth {
+        EndpointHealth {
+            threshold,
+            probe_interval,
+            runtime,
+            failures: AtomicUsize::new(0),
+            unhealthy: AtomicBool::new(false),
+            next_probe: Mutex::new(None),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        !self.unhealthy.load(Ordering::SeqCst)
+    }
+
+    /// Claims the probe of an unhealthy endpoint if it's due.
+    fn probe_due(&self) -> bool {
+        if self.is_healthy() {
+            return false;
+        }
+
+        let now = self.runtime.now();
+        let mut next_probe = self.next_probe.lock();
+        match *next_probe {
+            Some(at) if now < at => false,
+            _ => {
+                *next_probe = Some(now + self.probe_interval);
+                true
+            }
+        }
+    }
+
+    fn failed(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= self.threshold && !self.unhealthy.swap(true, Ordering::SeqCst) {
+            debug!("endpoint unhealthy after {} failures", failures);
+            *self.next_probe.lock() = Some(self.runtime.now() + self.probe_interval);
+        }
+    }
+
+    fn recovered(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+        if self.unhealthy.swap(false, Ordering::SeqCst) {
+            debug!("endpoint recovered");
+            *self.next_probe.lock() = None;
+        }
+    }
+}
+
+impl PoolEventListener for EndpointHealth {
+    fn on_factory_finish(&self, _elapsed: Duration, error: Option<&(dyn StdError + 'static)>) {
+        if error.is_some() {
+            self.failed();
+        }
+    }
+
+    fn on_health_check(&self, result: Result<bool, &(dyn StdError + 'static)>) {
+        if let Ok(true) = result {
+            return;
+        }
+        self.failed();
+    }
+
+    fn on_checkout(&self, _progress: TakeProgress) {
+        self.recovered();
+    }
+
+    fn on_acquire_timeout(&self, phase: Phase, _progress: TakeProgress) {
+        //a hanging factory never reports that it finished
+        if phase == Phase::Connecting {
+            self.failed();
+        }
+    }
+}
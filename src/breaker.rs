@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// The settings of the circuit breaker around the factory, see `PoolBuilder::circuit_breaker`.
+///
+/// After `failure_threshold` factory calls fail in a row the breaker opens, and takes which
+/// would call the factory fail with `Error::CircuitOpen` right away. Once `open_for` has
+/// passed it's half-open and lets `half_open_probes` calls through at once, it closes after
+/// `success_threshold` of them succeed and opens again as soon as one fails.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    open_for: Duration,
+    half_open_probes: usize,
+    success_threshold: usize,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: usize, open_for: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            half_open_probes: 1,
+            success_threshold: 1,
+        }
+    }
+
+    /// How many factory calls a half-open breaker lets through at once, 1 by default.
+    pub fn half_open_probes(mut self, probes: usize) -> CircuitBreaker {
+        self.half_open_probes = probes.max(1);
+        self
+    }
+
+    /// How many probes have to succeed to close the breaker, 1 by default.
+    pub fn success_threshold(mut self, successes: usize) -> CircuitBreaker {
+        self.success_threshold = successes.max(1);
+        self
+    }
+}
+
+/// The state of a circuit breaker, returned by `Pool::circuit_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// The factory is called as usual.
+    Closed,
+    /// The factory isn't called.
+    Open,
+    /// A limited number of factory calls probe whether the backend recovered.
+    HalfOpen,
+}
+
+pub(crate) struct Breaker {
+    config: CircuitBreaker,
+    state: Mutex<BreakerState>,
+}
+
+struct BreakerState {
+    state: CircuitState,
+    //factory calls which failed in a row while closed
+    failures: usize,
+    //probes which succeeded while half-open
+    successes: usize,
+    //probes in flight while half-open
+    probes: usize,
+    opened_at: Option<Instant>,
+}
+
+/// A factory call the breaker let through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Call {
+    Regular,
+    Probe,
+}
+
+impl Breaker {
+    pub(crate) fn new(config: CircuitBreaker) -> Breaker {
+        Breaker {
+            config,
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                failures: 0,
+                successes: 0,
+                probes: 0,
+                opened_at: None,
+            }),
+        }
+    }
+
+    pub(crate) fn state(&self, now: Instant) -> CircuitState {
+        let mut state = self.state.lock();
+        self.half_open_if_due(&mut state, now);
+        state.state
+    }
+
+    /// Asks to call the factory, `None` if the breaker doesn't let the call through.
+    pub(crate) fn try_call(&self, now: Instant) -> Option<Call> {
+        let mut state = self.state.lock();
+        self.half_open_if_due(&mut state, now);
+        match state.state {
+            CircuitState::Closed => Some(Call::Regular),
+            CircuitState::Open => None,
+            CircuitState::HalfOpen if state.probes < self.config.half_open_probes => {
+                state.probes += 1;
+                Some(Call::Probe)
+            }
+            CircuitState::HalfOpen => None,
+        }
+    }
+
+    /// Records the outcome of a call which was let through.
+    pub(crate) fn record(&self, call: Call, success: bool, now: Instant) {
+        let mut state = self.state.lock();
+        match (call, state.state) {
+            (Call::Regular, CircuitState::Closed) if success => state.failures = 0,
+            (Call::Regular, CircuitState::Closed) => {
+                state.failures += 1;
+                if state.failures >= self.config.failure_threshold {
+                    debug!("circuit breaker open after {} failures", state.failures);
+                    self.open(&mut state, now);
+                }
+            }
+            (Call::Probe, CircuitState::HalfOpen) => {
+                state.probes = state.probes.saturating_sub(1);
+                if !success {
+                    debug!("circuit breaker probe failed, open again");
+                    self.open(&mut state, now);
+                    return;
+                }
+
+                state.successes += 1;
+                if state.successes >= self.config.success_threshold {
+                    debug!("circuit breaker closed");
+                    state.state = CircuitState::Closed;
+                    state.failures = 0;
+                    state.opened_at = None;
+                }
+            }
+            //calls which started before the breaker changed state don't count
+            _ => {}
+        }
+    }
+
+    /// Forgets a call which was dropped before it completed.
+    pub(crate) fn cancel(&self, call: Call) {
+        let mut state = self.state.lock();
+        if call == Call::Probe && state.state == CircuitState::HalfOpen {
+            state.probes = state.probes.saturating_sub(1);
+        }
+    }
+
+    fn open(&self, state: &mut BreakerState, now: Instant) {
+        state.state = CircuitState::Open;
+        state.opened_at = Some(now);
+        state.successes = 0;
+        state.probes = 0;
+    }
+
+    fn half_open_if_due(&self, state: &mut BreakerState, now: Instant) {
+        if state.state != CircuitState::Open {
+            return;
+        }
+
+        let opened_at = state.opened_at.unwrap_or(now);
+        if now.duration_since(opened_at) >= self.config.open_for {
+            debug!("circuit breaker half-open");
+            state.state = CircuitState::HalfOpen;
+        }
+    }
+}
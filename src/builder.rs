@@ -1,46 +1,97 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
 use std::time::Duration;
 
 use futures::Future;
-use parking_lot::RwLock;
-use tokio::io::Result;
+use parking_lot::Mutex;
 
-use crate::backoff::BackoffStrategy;
-use crate::factory::ObjectFactory;
+use crate::backoff::{BackoffStrategy, ExponentialBackoff};
+use crate::breaker::{Breaker, CircuitBreaker};
+use crate::events::{Listeners, NoEvents, PoolEventListener};
+use crate::factory::{ObjectDestroyer, ObjectFactory};
+use crate::health::HealthCheck;
+use crate::keyed::SharedLimit;
 use crate::object::PoolObject;
-use crate::pool::Pool;
+use crate::pool::{Pool, PoolInner};
+use crate::runtime::{default_runtime, Runtime};
+use crate::waiters::Waiters;
 
 pub struct PoolBuilder<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
-    _factory: Option<Arc<ObjectFactory<T>>>,
+    _factory: Option<Box<ObjectFactory<T>>>,
+    _destroyer: Option<Box<ObjectDestroyer<T>>>,
     _timeout: Option<Duration>,
     _max_tries: Option<usize>,
+    _connect_max_tries: Option<usize>,
     _capacity: Option<usize>,
+    _max_size: Option<usize>,
+    _min_idle: Option<usize>,
+    _idle_timeout: Option<Duration>,
+    _max_lifetime: Option<Duration>,
+    _max_lifetime_jitter: Duration,
+    _maintenance_interval: Duration,
+    _initialize_concurrency: Option<usize>,
     _backoff: BackoffStrategy,
+    _connect_backoff: BackoffStrategy,
+    _health_check: HealthCheck,
+    _runtime: Option<Arc<dyn Runtime>>,
+    _event_listener: Option<Arc<dyn PoolEventListener>>,
+    _shared: Option<Arc<SharedLimit<T>>>,
+    _circuit_breaker: Option<CircuitBreaker>,
 }
 
 impl<T> PoolBuilder<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
     pub fn new() -> PoolBuilder<T> {
         PoolBuilder {
             _factory: None,
+            _destroyer: None,
             _timeout: Some(Duration::from_secs(10)),
             _max_tries: Some(10),
+            _connect_max_tries: Some(10),
             _capacity: None,
+            _max_size: None,
+            _min_idle: None,
+            _idle_timeout: None,
+            _max_lifetime: None,
+            _max_lifetime_jitter: Duration::from_secs(0),
+            _maintenance_interval: Duration::from_secs(30),
+            _initialize_concurrency: Some(10),
             _backoff: BackoffStrategy::None,
+            _connect_backoff: BackoffStrategy::Exponential(
+                ExponentialBackoff::from_millis(2)
+                    .factor(5)
+                    .max_delay(Duration::from_secs(1)),
+            ),
+            _health_check: HealthCheck::OnCheckout,
+            _runtime: None,
+            _event_listener: None,
+            _shared: None,
+            _circuit_breaker: None,
         }
     }
 
     pub fn factory<F>(mut self, factory: impl Fn() -> F + Send + Sync + 'static) -> Self
     where
-        F: Future<Output = Result<T>> + 'static,
+        F: Future<Output = Result<T, T::Error>> + Send + 'static,
     {
-        self._factory = Some(Arc::new(move || Box::pin(factory())));
+        self._factory = Some(Box::new(move || Box::pin(factory())));
+        self
+    }
+
+    /// Called with every object the pool discards, including the idle ones left when the
+    /// last `Pool` clone is dropped. The returned future is spawned so that the object can
+    /// be closed gracefully.
+    pub fn destroyer<F>(mut self, destroyer: impl Fn(T) -> F + Send + Sync + 'static) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self._destroyer = Some(Box::new(move |object| Box::pin(destroyer(object))));
         self
     }
 
@@ -49,6 +100,7 @@ where
         self
     }
 
+    /// How many objects failing `test_poll` a single take goes through before giving up.
     pub fn max_tries(mut self, max_tries: Option<usize>) -> Self {
         self._max_tries = max_tries;
         self
@@ -59,21 +111,157 @@ where
         self
     }
 
+    /// Limits the number of live objects - idle, checked out and being created.
+    /// When the limit is reached `Pool::take` waits for an object to be returned.
+    pub fn max_size(mut self, max_size: Option<usize>) -> Self {
+        self._max_size = max_size;
+        self
+    }
+
+    /// The number of idle objects `Pool::maintenance` keeps in the pool.
+    pub fn min_idle(mut self, min_idle: Option<usize>) -> Self {
+        self._min_idle = min_idle;
+        self
+    }
+
+    /// Idle objects older than `idle_timeout` are discarded instead of handed out.
+    /// They are also evicted by `Pool::maintenance`.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self._idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Objects older than `max_lifetime` are discarded instead of handed out or returned
+    /// to the pool, so that connections get rotated.
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> Self {
+        self._max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Shortens the `max_lifetime` of each object by a random amount up to `jitter`,
+    /// so that objects created together don't all expire at once.
+    pub fn max_lifetime_jitter(mut self, jitter: Duration) -> Self {
+        self._max_lifetime_jitter = jitter;
+        self
+    }
+
+    /// How often the future returned by `Pool::maintenance` runs.
+    pub fn maintenance_interval(mut self, interval: Duration) -> Self {
+        self._maintenance_interval = interval;
+        self
+    }
+
+    /// How many objects `Pool::initialize` creates at once, `None` creates all of them at once.
+    pub fn initialize_concurrency(mut self, concurrency: Option<usize>) -> Self {
+        self._initialize_concurrency = concurrency;
+        self
+    }
+
+    /// The delay between objects failing `test_poll`.
     pub fn backoff(mut self, backoff: BackoffStrategy) -> Self {
         self._backoff = backoff;
         self
     }
 
+    /// How many times a single take calls a failing factory before giving up.
+    pub fn connect_max_tries(mut self, max_tries: Option<usize>) -> Self {
+        self._connect_max_tries = max_tries;
+        self
+    }
+
+    /// The delay between failed factory calls, exponential from 10ms up to 1s by default.
+    pub fn connect_backoff(mut self, backoff: BackoffStrategy) -> Self {
+        self._connect_backoff = backoff;
+        self
+    }
+
+    /// When objects are tested with `PoolObject::test_poll`, `HealthCheck::OnCheckout` by default.
+    pub fn health_check(mut self, health_check: HealthCheck) -> Self {
+        self._health_check = health_check;
+        self
+    }
+
+    /// Stops calling the factory for a while once it keeps failing, takes which would
+    /// call it fail with `Error::CircuitOpen` instead.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self._circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// The timer and executor of the pool, `TokioRuntime` by default.
+    pub fn runtime(mut self, runtime: impl Runtime) -> Self {
+        self._runtime = Some(Arc::new(runtime));
+        self
+    }
+
+    /// The runtime the pool will run on, resolved ahead of `build` so that its clock
+    /// can be shared, e.g. with the health tracking of a `BalancedPool` endpoint.
+    pub(crate) fn shared_runtime(&mut self) -> Arc<dyn Runtime> {
+        self._runtime
+            .get_or_insert_with(|| default_runtime().expect("A pool runtime is required"))
+            .clone()
+    }
+
+    /// Receives the lifecycle events of the pool, e.g. to log or trace them.
+    pub fn event_listener(mut self, listener: impl PoolEventListener) -> Self {
+        self._event_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Adds a listener next to the one set with `event_listener`.
+    pub(crate) fn add_event_listener(mut self, listener: Arc<dyn PoolEventListener>) -> Self {
+        self._event_listener = Some(match self._event_listener.take() {
+            Some(current) => Arc::new(Listeners(vec![current, listener])),
+            None => listener,
+        });
+        self
+    }
+
+    /// Makes the pool a partition of a `KeyedPool` with a limit on all partitions.
+    pub(crate) fn shared_limit(mut self, shared: Arc<SharedLimit<T>>) -> Self {
+        self._shared = Some(shared);
+        self
+    }
+
     pub fn build(self) -> Pool<T> {
         Pool {
-            factory: self._factory.expect("A pool connector is required"),
-            objects: Arc::new(RwLock::new(VecDeque::with_capacity(
-                self._capacity.unwrap_or_else(|| 10),
-            ))),
-            backoff: self._backoff,
-            timeout: self._timeout,
-            max_tries: self._max_tries,
-            capacity: self._capacity,
+            inner: Arc::new(PoolInner {
+                factory: self._factory.expect("A pool connector is required"),
+                destroyer: self._destroyer,
+                objects: Mutex::new(VecDeque::with_capacity(
+                    self._capacity.unwrap_or_else(|| 10),
+                )),
+                backoff: self._backoff,
+                connect_backoff: self._connect_backoff,
+                health_check: self._health_check,
+                health_stats: Default::default(),
+                metrics: Default::default(),
+                events: self._event_listener.unwrap_or_else(|| Arc::new(NoEvents)),
+                runtime: self
+                    ._runtime
+                    .or_else(default_runtime)
+                    .expect("A pool runtime is required"),
+                shared: self._shared,
+                breaker: self._circuit_breaker.map(|breaker| Arc::new(Breaker::new(breaker))),
+                timeout: self._timeout,
+                max_tries: self._max_tries,
+                connect_max_tries: self._connect_max_tries,
+                capacity: self._capacity,
+                max_size: self._max_size,
+                min_idle: self._min_idle,
+                idle_timeout: self._idle_timeout,
+                max_lifetime: self._max_lifetime,
+                max_lifetime_jitter: self._max_lifetime_jitter,
+                maintenance_interval: self._maintenance_interval,
+                initialize_concurrency: self._initialize_concurrency,
+                live: AtomicUsize::new(0),
+                waiters: Mutex::new(Waiters::new()),
+                closed: AtomicBool::new(false),
+                drains: Mutex::new(Vec::new()),
+                maintenance: Mutex::new(Vec::new()),
+                takers: Mutex::new(HashMap::new()),
+                next_taker: AtomicUsize::new(0),
+            }),
         }
     }
 }
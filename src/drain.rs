@@ -0,0 +1,47 @@
+use std::pin::Pin;
+use std::task::Context;
+
+use futures::{Future, Poll};
+
+use crate::object::PoolObject;
+use crate::pool::Pool;
+
+/// Resolves once every object of a closed pool is back and discarded, returned by `Pool::drain`.
+pub struct Drain<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pool: Pool<T>,
+}
+
+impl<T> Drain<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pub(crate) fn new(pool: Pool<T>) -> Drain<T> {
+        Drain { pool }
+    }
+}
+
+impl<T> Future for Drain<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        //register first so that the last release can't slip in between
+        {
+            let mut drains = self.pool.inner.drains.lock();
+            if !drains.iter().any(|waker| waker.will_wake(cx.waker())) {
+                drains.push(cx.waker().clone());
+            }
+        }
+
+        if self.pool.live() == 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
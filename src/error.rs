@@ -0,0 +1,126 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// The error returned by the pool, `E` is the `PoolObject::Error` of the factory,
+/// `test_poll` and `recycle_poll`.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The pool `timeout` elapsed, `phase` is what the take was still doing.
+    Timeout { phase: Phase },
+    /// The pool was closed with `Pool::close`.
+    Closed,
+    /// The circuit breaker is open after the factory kept failing, so the factory
+    /// wasn't called.
+    CircuitOpen,
+    /// The factory or `test_poll` kept failing, `last_error` is either
+    /// `Error::Factory` or `Error::Validation`.
+    MaxTriesExceeded {
+        last_error: Box<Error<E>>,
+        tries: usize,
+    },
+    /// The factory failed to create an object.
+    Factory(E),
+    /// `PoolObject::test_poll` failed.
+    Validation(E),
+}
+
+impl<E> Error<E> {
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match self {
+            Error::Closed => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_circuit_open(&self) -> bool {
+        match self {
+            Error::CircuitOpen => true,
+            _ => false,
+        }
+    }
+
+    /// The error returned by the factory or `test_poll`, if any.
+    pub fn inner(&self) -> Option<&E> {
+        match self {
+            Error::MaxTriesExceeded { last_error, .. } => last_error.inner(),
+            Error::Factory(err) | Error::Validation(err) => Some(err),
+            Error::Timeout { .. } | Error::Closed | Error::CircuitOpen => None,
+        }
+    }
+}
+
+impl<E> fmt::Display for Error<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Timeout { phase } => write!(f, "pool timed out while {}", phase),
+            Error::Closed => f.write_str("pool closed"),
+            Error::CircuitOpen => f.write_str("circuit breaker open"),
+            Error::MaxTriesExceeded { last_error, tries } => {
+                write!(f, "gave up after {} tries: {}", tries, last_error)
+            }
+            Error::Factory(err) => write!(f, "factory failed: {}", err),
+            Error::Validation(err) => write!(f, "object validation failed: {}", err),
+        }
+    }
+}
+
+impl<E> StdError for Error<E>
+where
+    E: StdError + 'static,
+{
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::MaxTriesExceeded { last_error, .. } => Some(&**last_error),
+            Error::Factory(err) | Error::Validation(err) => Some(err),
+            Error::Timeout { .. } | Error::Closed | Error::CircuitOpen => None,
+        }
+    }
+}
+
+impl From<Error<io::Error>> for io::Error {
+    fn from(err: Error<io::Error>) -> io::Error {
+        match err {
+            Error::Timeout { .. } => io::Error::new(io::ErrorKind::TimedOut, err),
+            Error::Closed => io::Error::new(io::ErrorKind::Other, err),
+            Error::CircuitOpen => io::Error::new(io::ErrorKind::ConnectionRefused, err),
+            Error::MaxTriesExceeded { last_error, .. } => io::Error::from(*last_error),
+            Error::Factory(err) | Error::Validation(err) => err,
+        }
+    }
+}
+
+/// The part of `Pool::take` which was still running when the timeout fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for an idle object or a free slot.
+    Waiting,
+    /// Waiting for the factory to create an object.
+    Connecting,
+    /// Waiting for `PoolObject::test_poll`.
+    Testing,
+    /// Waiting for the backoff delay after a failed factory call or `test_poll`.
+    Backoff,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phase = match self {
+            Phase::Waiting => "waiting for an object",
+            Phase::Connecting => "connecting",
+            Phase::Testing => "testing an object",
+            Phase::Backoff => "backing off",
+        };
+        f.write_str(phase)
+    }
+}
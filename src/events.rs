@@ -0,0 +1,96 @@
+use std::error::Error as StdError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Phase;
+
+/// How far a take got when an event fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TakeProgress {
+    /// The time since the take started.
+    pub elapsed: Duration,
+    /// How many objects failed their health check so far.
+    pub tries: usize,
+    /// How many factory calls failed so far.
+    pub connect_tries: usize,
+}
+
+/// Receives the lifecycle events of a pool, registered with `PoolBuilder::event_listener`.
+///
+/// Every method does nothing by default. They are called on the task which drives the pool,
+/// never while the pool holds a lock, so they should return quickly.
+pub trait PoolEventListener: Send + Sync + 'static {
+    /// The factory was called for a new object.
+    fn on_factory_start(&self) {}
+
+    /// A factory call completed after `elapsed`, `error` is set if it failed.
+    fn on_factory_finish(&self, _elapsed: Duration, _error: Option<&(dyn StdError + 'static)>) {}
+
+    /// `PoolObject::test_poll` returned, `Ok(false)` for an unusable object.
+    fn on_health_check(&self, _result: Result<bool, &(dyn StdError + 'static)>) {}
+
+    /// A take handed out an object.
+    fn on_checkout(&self, _progress: TakeProgress) {}
+
+    /// A returned object went back to the pool or to a waiting take.
+    fn on_checkin(&self) {}
+
+    /// An idle object was discarded to make room because the pool was at `capacity`.
+    fn on_evict(&self) {}
+
+    /// A take waits for `delay` after a failed factory call or health check.
+    fn on_backoff(&self, _delay: Duration, _progress: TakeProgress) {}
+
+    /// A take ran out of `timeout` while in `phase`.
+    fn on_acquire_timeout(&self, _phase: Phase, _progress: TakeProgress) {}
+}
+
+/// The listener of pools built without one.
+pub(crate) struct NoEvents;
+
+impl PoolEventListener for NoEvents {}
+
+/// Forwards the events to several listeners, in order.
+pub(crate) struct Listeners(pub(crate) Vec<Arc<dyn PoolEventListener>>);
+
+impl PoolEventListener for Listeners {
+    fn on_factory_start(&self) {
+        self.0.iter().for_each(|listener| listener.on_factory_start());
+    }
+
+    fn on_factory_finish(&self, elapsed: Duration, error: Option<&(dyn StdError + 'static)>) {
+        self.0
+            .iter()
+            .for_each(|listener| listener.on_factory_finish(elapsed, error));
+    }
+
+    fn on_health_check(&self, result: Result<bool, &(dyn StdError + 'static)>) {
+        self.0
+            .iter()
+            .for_each(|listener| listener.on_health_check(result));
+    }
+
+    fn on_checkout(&self, progress: TakeProgress) {
+        self.0.iter().for_each(|listener| listener.on_checkout(progress));
+    }
+
+    fn on_checkin(&self) {
+        self.0.iter().for_each(|listener| listener.on_checkin());
+    }
+
+    fn on_evict(&self) {
+        self.0.iter().for_each(|listener| listener.on_evict());
+    }
+
+    fn on_backoff(&self, delay: Duration, progress: TakeProgress) {
+        self.0
+            .iter()
+            .for_each(|listener| listener.on_backoff(delay, progress));
+    }
+
+    fn on_acquire_timeout(&self, phase: Phase, progress: TakeProgress) {
+        self.0
+            .iter()
+            .for_each(|listener| listener.on_acquire_timeout(phase, progress));
+    }
+}
@@ -1,7 +1,117 @@
+use std::error::Error as StdError;
 use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Context;
+use std::time::Instant;
 
-use futures::Future;
-use std::io::Result;
+use futures::{ready, Future, Poll};
+
+use crate::breaker::{Breaker, Call};
+use crate::events::PoolEventListener;
+use crate::metrics::MetricsCounters;
+use crate::object::PoolObject;
+use crate::pool::Pool;
+use crate::runtime::Runtime;
 
 pub type ObjectFactory<T> =
-    dyn Fn() -> Pin<Box<dyn Future<Output = Result<T>>>> + 'static + Send + Sync;
+    dyn Fn() -> Pin<Box<dyn Future<Output = Result<T, <T as PoolObject>::Error>> + Send>>
+        + 'static
+        + Send
+        + Sync;
+
+pub type LocalObjectFactory<T> =
+    dyn Fn() -> Pin<Box<dyn Future<Output = Result<T, <T as PoolObject>::Error>>>> + 'static;
+
+pub type KeyedObjectFactory<K, T> =
+    dyn Fn(&K) -> Pin<Box<dyn Future<Output = Result<T, <T as PoolObject>::Error>> + Send>>
+        + 'static
+        + Send
+        + Sync;
+
+pub type ObjectDestroyer<T> =
+    dyn Fn(T) -> Pin<Box<dyn Future<Output = ()> + Send>> + 'static + Send + Sync;
+
+/// A factory call in flight, counted in `PoolState::pending` until it completes or is dropped.
+/// Its start and finish are reported to the event listener, and its outcome to the
+/// circuit breaker if the pool has one.
+pub(crate) struct FactoryFuture<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    future: Pin<Box<dyn Future<Output = Result<T, T::Error>> + Send>>,
+    metrics: Arc<MetricsCounters>,
+    events: Arc<dyn PoolEventListener>,
+    runtime: Arc<dyn Runtime>,
+    started_at: Instant,
+    breaker: Option<(Arc<Breaker>, Call)>,
+    done: bool,
+}
+
+impl<T> FactoryFuture<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pub(crate) fn new(pool: &Pool<T>, breaker: Option<(Arc<Breaker>, Call)>) -> FactoryFuture<T> {
+        let inner = &pool.inner;
+        inner.metrics.creating.fetch_add(1, Ordering::SeqCst);
+        inner.events.on_factory_start();
+        FactoryFuture {
+            future: (inner.factory)(),
+            metrics: inner.metrics.clone(),
+            events: inner.events.clone(),
+            runtime: inner.runtime.clone(),
+            started_at: inner.runtime.now(),
+            breaker,
+            done: false,
+        }
+    }
+
+    /// Counts a call which hung until its take timed out as a failed one,
+    /// dropping it afterwards doesn't count it again.
+    pub(crate) fn timed_out(&mut self) {
+        if let Some((breaker, call)) = self.breaker.take() {
+            breaker.record(call, false, self.runtime.now());
+        }
+    }
+}
+
+impl<T> Future for FactoryFuture<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    type Output = Result<T, T::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let res = ready!(self.future.as_mut().poll(cx));
+        self.done = true;
+        self.metrics.creating.fetch_sub(1, Ordering::SeqCst);
+        if res.is_ok() {
+            self.metrics.created();
+        }
+
+        let now = self.runtime.now();
+        if let Some((ref breaker, call)) = self.breaker {
+            breaker.record(call, res.is_ok(), now);
+        }
+
+        let elapsed = now.duration_since(self.started_at);
+        let error = res.as_ref().err().map(|err| err as &(dyn StdError + 'static));
+        self.events.on_factory_finish(elapsed, error);
+        Poll::Ready(res)
+    }
+}
+
+impl<T> Drop for FactoryFuture<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    fn drop(&mut self) {
+        if !self.done {
+            self.metrics.creating.fetch_sub(1, Ordering::SeqCst);
+            if let Some((ref breaker, call)) = self.breaker {
+                breaker.cancel(call);
+            }
+        }
+    }
+}
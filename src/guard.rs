@@ -1,63 +1,366 @@
+use std::pin::Pin;
+use std::task::Context;
+
+use futures::task::noop_waker_ref;
+use futures::{ready, Future, FutureExt, Poll};
+
 use crate::object::PoolObject;
-use crate::pool::Pool;
+use crate::pool::{Entry, Pool};
 
 pub struct PoolGuard<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
-    object: Option<T>,
+    entry: Option<Entry<T>>,
+    //set by `invalidate`, the object is discarded on drop
+    invalid: bool,
     pool: Pool<T>,
 }
 
 impl<T> PoolGuard<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
-    pub(crate) fn new(object: T, pool: Pool<T>) -> PoolGuard<T> {
+    pub(crate) fn new(entry: Entry<T>, pool: Pool<T>) -> PoolGuard<T> {
         PoolGuard {
-            object: Some(object),
+            entry: Some(entry),
+            invalid: false,
             pool,
         }
     }
+
+    /// Whether the object outlived the pool's `max_lifetime`.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.entry
+            .as_ref()
+            .map(|entry| entry.is_expired(self.pool.now()))
+            .unwrap_or(false)
+    }
 }
 
 impl<T> PoolGuard<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
+    /// Discards the object instead of returning it to the pool.
+    pub(crate) fn discard(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.pool.discard(entry.object);
+        }
+    }
+
+    /// Marks the object as broken, e.g. after a protocol error. It's discarded instead of
+    /// returned to the pool when the guard is dropped, and a waiting take may create
+    /// a replacement.
+    pub fn invalidate(&mut self) {
+        self.invalid = true;
+    }
+
     pub fn detach(&mut self) -> Option<T> {
-        let object = self.object.take();
+        let object = self.entry.take().map(|entry| entry.object);
+        if object.is_some() {
+            //the pool doesn't own the object anymore
+            self.pool.release();
+        }
         object
     }
 }
 
+impl<T> PoolGuard<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    /// Makes a guard for a part of the object, e.g. a cache inside a connection.
+    /// The object returns to the pool when the mapped guard is dropped.
+    ///
+    /// Like `parking_lot`'s mapped guards this is called as `PoolGuard::map(guard, ..)`,
+    /// so that it doesn't hide a `map` method of the object.
+    pub fn map<U, F>(guard: Self, f: F) -> MappedPoolGuard<T, U>
+    where
+        U: ?Sized,
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let guard = OwnedGuard::new(guard);
+        //the object stays where it is on the heap until `guard` is dropped
+        let value = f(unsafe { &mut **guard.0 }) as *mut U;
+        MappedPoolGuard { guard, value }
+    }
+
+    /// Like `map`, but `f` may decline, in which case the guard is given back.
+    pub fn try_map<U, F>(guard: Self, f: F) -> Result<MappedPoolGuard<T, U>, Self>
+    where
+        U: ?Sized,
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let guard = OwnedGuard::new(guard);
+        match f(unsafe { &mut **guard.0 }) {
+            Some(value) => {
+                let value = value as *mut U;
+                Ok(MappedPoolGuard { guard, value })
+            }
+            None => Err(guard.into_inner()),
+        }
+    }
+}
+
 impl<T> std::ops::Deref for PoolGuard<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        self.object.as_ref().expect("deref PoolGuard no inner")
+        &self.entry.as_ref().expect("deref PoolGuard no inner").object
     }
 }
 
 impl<T> std::ops::DerefMut for PoolGuard<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.object.as_mut().expect("deref PoolGuard no inner")
+        &mut self.entry.as_mut().expect("deref PoolGuard no inner").object
     }
 }
 
 impl<T> Drop for PoolGuard<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            if self.invalid {
+                debug!("object invalidated, discarding it");
+                self.pool.discard(entry.object);
+                return;
+            }
+
+            if entry.is_expired(self.pool.now()) {
+                debug!("object outlived max_lifetime, discarding it");
+                self.pool.discard(entry.object);
+                return;
+            }
+
+            if self.pool.is_closed() {
+                self.pool.discard(entry.object);
+                return;
+            }
+
+            let mut recycle = Recycle {
+                entry: Some(entry),
+                recycled: false,
+                test: self.pool.inner.health_check.on_checkin(),
+                pool: self.pool.clone(),
+            };
+
+            //most objects recycle right away, the rest finish on a spawned task
+            let mut cx = Context::from_waker(noop_waker_ref());
+            if recycle.poll_unpin(&mut cx).is_pending() {
+                debug!("object recycle pending, spawning it");
+                self.pool.inner.runtime.spawn(Box::pin(recycle));
+            }
+        }
+    }
+}
+
+/// A guard for a part of a pooled object, returned by `PoolGuard::map`.
+/// The object returns to the pool when it's dropped.
+pub struct MappedPoolGuard<T, U>
+where
+    T: PoolObject + Send + 'static,
+    U: ?Sized,
+{
+    guard: OwnedGuard<T>,
+    //points into the object owned by `guard`
+    value: *mut U,
+}
+
+//the mapped guard owns the object and only hands out `U`, like a `PoolGuard` does with `T`,
+//so it's only sent or shared where the guard it wraps could be
+unsafe impl<T, U> Send for MappedPoolGuard<T, U>
+where
+    T: PoolObject + Send + 'static,
+    U: ?Sized + Send,
+    PoolGuard<T>: Send,
+{
+}
+
+unsafe impl<T, U> Sync for MappedPoolGuard<T, U>
+where
+    T: PoolObject + Send + 'static,
+    U: ?Sized + Sync,
+    PoolGuard<T>: Sync,
+{
+}
+
+impl<T, U> MappedPoolGuard<T, U>
+where
+    T: PoolObject + Send + 'static,
+    U: ?Sized,
+{
+    /// Like `PoolGuard::invalidate`, the object is discarded when the mapped guard is dropped.
+    /// Called as `MappedPoolGuard::invalidate(&mut guard)`.
+    pub fn invalidate(guard: &mut Self) {
+        //only the flag is written, `value` keeps pointing into the object
+        unsafe { (*guard.guard.0).invalid = true }
+    }
+
+    /// Maps the guard further, e.g. to a field of the part it already points to.
+    pub fn map<V, F>(guard: Self, f: F) -> MappedPoolGuard<T, V>
+    where
+        V: ?Sized,
+        F: FnOnce(&mut U) -> &mut V,
+    {
+        let value = f(unsafe { &mut *guard.value }) as *mut V;
+        MappedPoolGuard {
+            guard: guard.guard,
+            value,
+        }
+    }
+
+    /// Like `map`, but `f` may decline, in which case the guard is given back.
+    pub fn try_map<V, F>(guard: Self, f: F) -> Result<MappedPoolGuard<T, V>, Self>
+    where
+        V: ?Sized,
+        F: FnOnce(&mut U) -> Option<&mut V>,
+    {
+        match f(unsafe { &mut *guard.value }) {
+            Some(value) => {
+                let value = value as *mut V;
+                Ok(MappedPoolGuard {
+                    guard: guard.guard,
+                    value,
+                })
+            }
+            None => Err(guard),
+        }
+    }
+}
+
+impl<T, U> std::ops::Deref for MappedPoolGuard<T, U>
+where
+    T: PoolObject + Send + 'static,
+    U: ?Sized,
+{
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.value }
+    }
+}
+
+impl<T, U> std::ops::DerefMut for MappedPoolGuard<T, U>
+where
+    T: PoolObject + Send + 'static,
+    U: ?Sized,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.value }
+    }
+}
+
+/// A boxed `PoolGuard` behind a raw pointer, so that references into its object stay
+/// valid while the mapped guard moves. Dropping it returns the object to the pool.
+struct OwnedGuard<T>(*mut PoolGuard<T>)
+where
+    T: PoolObject + Send + 'static;
+
+impl<T> OwnedGuard<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    fn new(guard: PoolGuard<T>) -> OwnedGuard<T> {
+        OwnedGuard(Box::into_raw(Box::new(guard)))
+    }
+
+    fn into_inner(self) -> PoolGuard<T> {
+        let guard = unsafe { Box::from_raw(self.0) };
+        std::mem::forget(self);
+        *guard
+    }
+}
+
+impl<T> Drop for OwnedGuard<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.0) });
+    }
+}
+
+/// Drives `PoolObject::recycle_poll` of a returned object, and `test_poll` if the pool
+/// checks objects on checkin, then puts it back in the pool.
+struct Recycle<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    entry: Option<Entry<T>>,
+    recycled: bool,
+    test: bool,
+    pool: Pool<T>,
+}
+
+//the object is never pinned
+impl<T> Unpin for Recycle<T> where T: PoolObject + Send + 'static {}
+
+impl<T> Recycle<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    fn poll_usable(&mut self, cx: &mut Context) -> Poll<Result<bool, T::Error>> {
+        let entry = self.entry.as_mut().expect("Recycle polled after completion");
+        if !self.recycled {
+            ready!(entry.object.recycle_poll(cx))?;
+            self.recycled = true;
+        }
+
+        if !self.test {
+            return Poll::Ready(Ok(true));
+        }
+
+        let res = ready!(entry.object.test_poll(cx));
+        self.pool.health_checked(&res);
+        Poll::Ready(res)
+    }
+}
+
+impl<T> Future for Recycle<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let res = ready!(self.poll_usable(cx));
+        let entry = self.entry.take().unwrap();
+        match res {
+            Ok(true) => {
+                self.pool.inner.events.on_checkin();
+                self.pool.put_idle(entry);
+            }
+            Ok(false) => {
+                debug!("object unusable on checkin");
+                self.pool.discard(entry.object);
+            }
+            Err(err) => {
+                debug!("object recycle or checkin test failed, err={}", &err);
+                self.pool.discard(entry.object);
+            }
+        }
+
+        Poll::Ready(())
+    }
+}
+
+impl<T> Drop for Recycle<T>
+where
+    T: PoolObject + Send + 'static,
 {
     fn drop(&mut self) {
-        if let Some(object) = self.object.take() {
-            self.pool.put(object);
+        //never finished recycling, e.g. there was no executor to spawn it on
+        if let Some(entry) = self.entry.take() {
+            self.pool.discard(entry.object);
         }
     }
 }
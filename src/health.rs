@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// When the pool runs `PoolObject::test_poll`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthCheck {
+    /// Before every object is handed out, brand new ones included.
+    OnCheckout,
+    /// When an object is returned to the pool, failing objects are discarded.
+    OnCheckin,
+    /// Before handing out objects which were idle for longer than the duration.
+    IdleLongerThan(Duration),
+    /// Objects are never tested.
+    Never,
+}
+
+impl HealthCheck {
+    pub(crate) fn on_checkout(&self, idle_for: Option<Duration>) -> bool {
+        match *self {
+            HealthCheck::OnCheckout => true,
+            HealthCheck::IdleLongerThan(threshold) => idle_for
+                .map(|idle_for| idle_for >= threshold)
+                .unwrap_or(false),
+            HealthCheck::OnCheckin | HealthCheck::Never => false,
+        }
+    }
+
+    pub(crate) fn on_checkin(&self) -> bool {
+        *self == HealthCheck::OnCheckin
+    }
+}
+
+/// The `test_poll` results of a pool so far, returned by `Pool::health_check_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealthCheckStats {
+    pub passed: usize,
+    /// `test_poll` returned `Ok(false)`.
+    pub unusable: usize,
+    /// `test_poll` returned an error.
+    pub failed: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct HealthCheckCounters {
+    passed: AtomicUsize,
+    unusable: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl HealthCheckCounters {
+    pub(crate) fn record<E>(&self, res: &Result<bool, E>) {
+        let counter = match res {
+            Ok(true) => &self.passed,
+            Ok(false) => &self.unusable,
+            Err(_) => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn snapshot(&self) -> HealthCheckStats {
+        HealthCheckStats {
+            passed: self.passed.load(Ordering::SeqCst),
+            unusable: self.unusable.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use futures::channel::oneshot::Receiver;
+use futures::Future;
+use parking_lot::Mutex;
+
+use crate::builder::PoolBuilder;
+use crate::factory::KeyedObjectFactory;
+use crate::guard::PoolGuard;
+use crate::object::PoolObject;
+use crate::pool::{Entry, Pool, PoolInner};
+use crate::taker::PoolTaker;
+use crate::waiters::{Handoff, Waiters};
+
+/// Applies the settings of `KeyedPoolBuilder::partition` to the builder of a partition.
+type PartitionConfig<T> = dyn Fn(PoolBuilder<T>) -> PoolBuilder<T> + Send + Sync;
+
+/// A pool with one partition per key, e.g. per host of a sharded database.
+///
+/// Every partition is a `Pool` with its own idle queue, configured with
+/// `KeyedPoolBuilder::partition`. Objects always go back to the partition they were
+/// taken from. `KeyedPoolBuilder::max_size` limits the live objects of all partitions
+/// together, once it's reached a take evicts an idle object of another partition
+/// or waits for one to be discarded.
+pub struct KeyedPool<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: PoolObject + Send + 'static,
+{
+    inner: Arc<KeyedPoolInner<K, T>>,
+}
+
+struct KeyedPoolInner<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: PoolObject + Send + 'static,
+{
+    factory: Arc<KeyedObjectFactory<K, T>>,
+    configure: Box<PartitionConfig<T>>,
+    shared: Option<Arc<SharedLimit<T>>>,
+    partitions: Mutex<HashMap<K, Pool<T>>>,
+    closed: AtomicBool,
+}
+
+impl<K, T> Clone for KeyedPool<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: PoolObject + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        KeyedPool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, T> KeyedPool<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: PoolObject + Send + 'static,
+{
+    pub fn builder() -> KeyedPoolBuilder<K, T> {
+        KeyedPoolBuilder::new()
+    }
+
+    /// The pool of `key`, created on first use. It can be used like any `Pool`,
+    /// e.g. to spawn its `maintenance` or read its `state`.
+    pub fn partition(&self, key: &K) -> Pool<T> {
+        let mut partitions = self.inner.partitions.lock();
+        if let Some(pool) = partitions.get(key) {
+            return pool.clone();
+        }
+
+        let factory = self.inner.factory.clone();
+        let factory_key = key.clone();
+        let mut builder = (self.inner.configure)(Pool::builder());
+        builder = builder.factory(move || factory(&factory_key));
+        if let Some(ref shared) = self.inner.shared {
+            builder = builder.shared_limit(shared.clone());
+        }
+
+        let pool = builder.build();
+        if let Some(ref shared) = self.inner.shared {
+            shared.pools.lock().push(pool.downgrade());
+        }
+        if self.is_closed() {
+            pool.close();
+        }
+        partitions.insert(key.clone(), pool.clone());
+        pool
+    }
+
+    /// Takes an object of `key`, the returned future doesn't borrow the key.
+    pub fn take(&self, key: &K) -> PoolTaker<T> {
+        PoolTaker::new(self.partition(key))
+    }
+
+    pub fn try_take(&self, key: &K) -> Option<PoolGuard<T>> {
+        self.partition(key).try_take()
+    }
+
+    /// The keys which have a partition.
+    pub fn keys(&self) -> Vec<K> {
+        self.inner.partitions.lock().keys().cloned().collect()
+    }
+
+    /// Closes every partition, including the ones created afterwards.
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::SeqCst);
+        let partitions: Vec<Pool<T>> = self.inner.partitions.lock().values().cloned().collect();
+        partitions.iter().for_each(|pool| pool.close());
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+}
+
+pub struct KeyedPoolBuilder<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: PoolObject + Send + 'static,
+{
+    _factory: Option<Arc<KeyedObjectFactory<K, T>>>,
+    _configure: Option<Box<PartitionConfig<T>>>,
+    _max_size: Option<usize>,
+}
+
+impl<K, T> KeyedPoolBuilder<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: PoolObject + Send + 'static,
+{
+    pub fn new() -> KeyedPoolBuilder<K, T> {
+        KeyedPoolBuilder {
+            _factory: None,
+            _configure: None,
+            _max_size: None,
+        }
+    }
+
+    /// Creates an object for the partition of the key.
+    pub fn factory<F>(mut self, factory: impl Fn(&K) -> F + Send + Sync + 'static) -> Self
+    where
+        F: Future<Output = Result<T, T::Error>> + Send + 'static,
+    {
+        self._factory = Some(Arc::new(move |key: &K| Box::pin(factory(key))));
+        self
+    }
+
+    /// Configures the pool of every partition, e.g. its `capacity`, backoff and
+    /// `max_size`, which limits the live objects of a single key.
+    /// The factory set on the `PoolBuilder` is replaced with the keyed one.
+    pub fn partition(
+        mut self,
+        configure: impl Fn(PoolBuilder<T>) -> PoolBuilder<T> + Send + Sync + 'static,
+    ) -> Self {
+        self._configure = Some(Box::new(configure));
+        self
+    }
+
+    /// Limits the live objects of all partitions together.
+    pub fn max_size(mut self, max_size: Option<usize>) -> Self {
+        self._max_size = max_size;
+        self
+    }
+
+    pub fn build(self) -> KeyedPool<K, T> {
+        KeyedPool {
+            inner: Arc::new(KeyedPoolInner {
+                factory: self._factory.expect("A pool connector is required"),
+                configure: self
+                    ._configure
+                    .unwrap_or_else(|| Box::new(|builder| builder)),
+                shared: self._max_size.map(|max_size| Arc::new(SharedLimit::new(max_size))),
+                partitions: Mutex::new(HashMap::new()),
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+}
+
+/// The limit on the live objects of all the partitions of a `KeyedPool`.
+/// A slot is taken along with the slot of the partition and given back with it.
+pub(crate) struct SharedLimit<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    max_size: usize,
+    live: AtomicUsize,
+    //takes of any partition waiting for a slot, oldest first, keyed by their partition
+    waiters: Mutex<Waiters<Entry<T>, usize>>,
+    pools: Mutex<Vec<Weak<PoolInner<T>>>>,
+}
+
+impl<T> SharedLimit<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    fn new(max_size: usize) -> SharedLimit<T> {
+        SharedLimit {
+            max_size,
+            live: AtomicUsize::new(0),
+            waiters: Mutex::new(Waiters::new()),
+            pools: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Takes a slot unless the limit is reached or other takes already wait for one.
+    pub(crate) fn try_reserve(&self) -> bool {
+        let waiters = self.waiters.lock();
+        waiters.is_empty() && self.reserve()
+    }
+
+    fn reserve(&self) -> bool {
+        let mut live = self.live.load(Ordering::SeqCst);
+        loop {
+            if live >= self.max_size {
+                return false;
+            }
+
+            match self
+                .live
+                .compare_exchange(live, live + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(actual) => live = actual,
+            }
+        }
+    }
+
+    /// Takes a slot even if the limit is reached, for objects added with `Pool::put`.
+    pub(crate) fn add(&self) {
+        self.live.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Gives a slot back, or hands it to the oldest waiting take.
+    pub(crate) fn release(&self) {
+        let mut waiters = self.waiters.lock();
+        if !waiters.hand_slot() {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Queues a take of `pool` for a slot, the receiver resolves with `Handoff::Slot`
+    /// once it owns one, or with an object of `pool` which was returned meanwhile.
+    pub(crate) fn wait(&self, pool: &Pool<T>) -> Receiver<Handoff<Entry<T>>> {
+        let mut waiters = self.waiters.lock();
+        let receiver = waiters.push_keyed(Self::partition(pool));
+        //a slot freed since the caller failed to reserve one goes to the oldest take
+        if self.reserve() && !waiters.hand_slot() {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+        }
+        receiver
+    }
+
+    /// Hands an object returned to `pool` to the oldest take of the same partition which
+    /// waits for a slot, gives it back if there is none.
+    pub(crate) fn hand_object(&self, pool: &Pool<T>, entry: Entry<T>) -> Result<(), Entry<T>> {
+        self.waiters
+            .lock()
+            .hand_object_keyed(&Self::partition(pool), entry)
+    }
+
+    fn partition(pool: &Pool<T>) -> usize {
+        &*pool.inner as *const PoolInner<T> as usize
+    }
+
+    pub(crate) fn has_waiters(&self) -> bool {
+        !self.waiters.lock().is_empty()
+    }
+
+    /// Wakes every waiting take to try again, e.g. because its partition was closed.
+    pub(crate) fn cancel_waiters(&self) {
+        self.waiters.lock().clear();
+    }
+
+    /// Discards an idle object of the partition with the most idle objects, other than
+    /// `pool`, to free a slot. Returns false if none of them has an idle object.
+    pub(crate) fn evict_idle(&self, pool: &Pool<T>) -> bool {
+        let others: Vec<Pool<T>> = {
+            let mut pools = self.pools.lock();
+            pools.retain(|inner| inner.strong_count() > 0);
+            pools
+                .iter()
+                .filter_map(Pool::upgrade)
+                .filter(|other| !Arc::ptr_eq(&other.inner, &pool.inner))
+                .collect()
+        };
+
+        others
+            .into_iter()
+            .filter(|other| other.size() > 0)
+            .max_by_key(|other| other.size())
+            .map(|other| other.evict_oldest())
+            .unwrap_or(false)
+    }
+}
@@ -10,24 +10,56 @@ mod pool;
 mod taker;
 mod guard;
 mod backoff;
+mod balance;
+mod breaker;
+mod maintenance;
+mod drain;
+mod health;
+mod metrics;
+mod error;
+mod events;
+mod keyed;
+mod local;
+mod runtime;
+mod waiters;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 
 #[macro_use]
 mod util;
 
 pub use crate::builder::PoolBuilder;
 pub use crate::object::PoolObject;
-pub use crate::pool::Pool;
-pub use crate::guard::PoolGuard;
+pub use crate::pool::{InitializeReport, Pool};
+pub use crate::guard::{MappedPoolGuard, PoolGuard};
 pub use crate::taker::PoolTaker;
+pub use crate::maintenance::Maintenance;
+pub use crate::drain::Drain;
+pub use crate::health::{HealthCheck, HealthCheckStats};
+pub use crate::metrics::{PoolMetrics, PoolState, WaitHistogram};
 pub use crate::backoff::*;
+pub use crate::balance::{BalanceStrategy, BalancedPool, BalancedPoolBuilder};
+pub use crate::breaker::{CircuitBreaker, CircuitState};
+pub use crate::error::{Error, Phase};
+pub use crate::events::{PoolEventListener, TakeProgress};
+pub use crate::keyed::{KeyedPool, KeyedPoolBuilder};
+pub use crate::local::{LocalPool, LocalPoolBuilder, LocalPoolGuard};
+pub use crate::runtime::{Runtime, RuntimeFuture};
+#[cfg(feature = "tokio")]
+pub use crate::runtime::TokioRuntime;
+#[cfg(feature = "async-std")]
+pub use crate::runtime::AsyncStdRuntime;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::*;
     use std::task::Context;
     use std::time::{Duration, Instant};
     use futures::Poll;
     use std::io::{Result, Error, ErrorKind};
+    use std::error::Error as StdError;
 
     macro_rules! tokio_run_async {
         ($e:expr) => {
@@ -42,11 +74,27 @@ mod tests {
     struct TcpConn(bool);
 
     impl PoolObject for TcpConn {
+        type Error = Error;
+
         fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool>> {
             Poll::Ready(Ok(self.0))
         }
     }
 
+    fn mock_builder(factory: &MockFactory, runtime: &MockRuntime) -> PoolBuilder<MockObject> {
+        let factory = factory.clone();
+        Pool::builder()
+            .factory(move || factory.create())
+            .runtime(runtime.clone())
+    }
+
+    fn take_now(pool: &Pool<MockObject>) -> PoolGuard<MockObject> {
+        match poll_once(&mut Box::pin(pool.take())) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("should take an object right away"),
+        }
+    }
+
     #[test]
     fn default_values() {
         let pool = Pool::<TcpConn>::builder()
@@ -163,12 +211,54 @@ mod tests {
         let fut = async move {
             match pool.take().await {
                 Ok(_) => panic!("should not work"),
-                Err(err) => assert_eq!(err.kind(), ErrorKind::TimedOut),
+                Err(err) => assert!(err.is_timeout()),
             };
         };
         tokio_run_async!(fut);
     }
 
+    #[test]
+    fn connect_timeout_stuck_factory() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory.script(MockCreate::Hang);
+        let pool = mock_builder(&factory, &runtime)
+            .timeout(Some(Duration::from_millis(100)))
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(99));
+        assert!(poll_once(&mut take).is_pending());
+
+        runtime.advance(Duration::from_millis(1));
+        match poll_once(&mut take) {
+            Poll::Ready(Err(crate::Error::Timeout { phase })) => assert_eq!(phase, Phase::Connecting),
+            _ => panic!("should time out while connecting"),
+        };
+    }
+
+    #[test]
+    fn connect_timeout_during_backoff() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory.script(MockCreate::FailingTest(MockError("broken pipe".to_string())));
+        let pool = mock_builder(&factory, &runtime)
+            .timeout(Some(Duration::from_millis(100)))
+            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(500)))
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+
+        runtime.advance(Duration::from_millis(100));
+        match poll_once(&mut take) {
+            Poll::Ready(Err(crate::Error::Timeout { phase })) => assert_eq!(phase, Phase::Backoff),
+            _ => panic!("should time out while backing off"),
+        };
+        assert_eq!(1, factory.calls());
+    }
+
     #[test]
     fn capacity_2() {
         let pool = Pool::<TcpConn>::builder()
@@ -208,224 +298,1788 @@ mod tests {
     }
 
     #[test]
-    fn initialize_10() {
+    fn max_size_2() {
+        use std::sync::{Arc, Mutex};
+
+        let c = Arc::new(Mutex::new(0));
+        let cc = c.clone();
         let pool = Pool::<TcpConn>::builder()
-            .factory(|| futures::future::ok(TcpConn(true)))
-            .capacity(None)
+            .factory(move || {
+                let mut cc = cc.lock().unwrap();
+                *cc = (*cc) + 1;
+                futures::future::ok(TcpConn(true))
+            })
+            .max_size(Some(2))
+            .timeout(Some(Duration::from_millis(100)))
             .build();
 
         let fut = async move {
-            pool.initialize(10).await.unwrap();
-            assert_eq!(pool.size(), 10);
+            let first = pool.take().await.unwrap();
+            let _second = pool.take().await.unwrap();
+
+            match pool.take().await {
+                Ok(_) => panic!("should not create a third object"),
+                Err(err) => assert!(err.is_timeout()),
+            };
+
+            drop(first);
+            pool.take().await.unwrap();
         };
         tokio_run_async!(fut);
+        assert_eq!(2, *c.lock().unwrap());
     }
 
     #[test]
-    fn initialize_10_capacity_8() {
+    fn max_size_counts_detached_objects_as_gone() {
         let pool = Pool::<TcpConn>::builder()
             .factory(|| futures::future::ok(TcpConn(true)))
-            .capacity(Some(8))
+            .max_size(Some(1))
+            .timeout(Some(Duration::from_millis(100)))
             .build();
 
         let fut = async move {
-            pool.initialize(10).await.unwrap();
-            assert_eq!(pool.size(), 8);
+            pool.take().await.unwrap().detach().unwrap();
+            pool.take().await.unwrap();
         };
         tokio_run_async!(fut);
     }
 
     #[test]
-    fn initialize_10_capacity_11() {
+    fn waiters_are_served_in_order() {
+        use futures::Future;
+        use std::pin::Pin;
+
         let pool = Pool::<TcpConn>::builder()
             .factory(|| futures::future::ok(TcpConn(true)))
-            .capacity(Some(11))
+            .max_size(Some(1))
             .build();
 
         let fut = async move {
-            pool.initialize(10).await.unwrap();
-            assert_eq!(pool.size(), 10);
+            let object = pool.take().await.unwrap();
+            let mut first = PoolTaker::new(pool.clone());
+            let mut second = PoolTaker::new(pool.clone());
+            futures::future::poll_fn(|cx| {
+                assert!(Pin::new(&mut first).poll(cx).is_pending());
+                assert!(Pin::new(&mut second).poll(cx).is_pending());
+                Poll::Ready(())
+            })
+            .await;
+
+            drop(object);
+            futures::future::poll_fn(|cx| {
+                assert!(Pin::new(&mut second).poll(cx).is_pending());
+                Poll::Ready(())
+            })
+            .await;
+
+            let object = first.await.unwrap();
+            assert_eq!(0, pool.size());
+            drop(object);
+            second.await.unwrap();
         };
         tokio_run_async!(fut);
     }
 
     #[test]
-    fn initialize_10_destroy_3() {
+    fn waiter_creates_object_when_slot_is_freed() {
+        use std::sync::{Arc, Mutex};
+
+        let c = Arc::new(Mutex::new(0));
+        let cc = c.clone();
         let pool = Pool::<TcpConn>::builder()
-            .factory(|| futures::future::ok(TcpConn(true)))
+            .factory(move || {
+                let mut cc = cc.lock().unwrap();
+                *cc = (*cc) + 1;
+                futures::future::ok(TcpConn(true))
+            })
+            .max_size(Some(1))
             .build();
 
         let fut = async move {
-            pool.initialize(10).await.unwrap();
-            pool.destroy(3);
-            assert_eq!(pool.size(), 7);
+            let mut object = pool.take().await.unwrap();
+            let waiting = PoolTaker::new(pool.clone());
+            object.detach().unwrap();
+            waiting.await.unwrap();
         };
         tokio_run_async!(fut);
+        assert_eq!(2, *c.lock().unwrap());
     }
 
     #[test]
-    fn initialize_10_destroy_11() {
-        let pool = Pool::<TcpConn>::builder()
-            .factory(|| futures::future::ok(TcpConn(true)))
+    fn pending_test_poll_is_not_polled_again_until_woken() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let polls = Arc::new(AtomicUsize::new(0));
+        let p = polls.clone();
+        let pool = Pool::<SlowConn>::builder()
+            .factory(move || futures::future::ok(SlowConn(p.clone())))
             .build();
 
         let fut = async move {
-            pool.initialize(10).await.unwrap();
-            pool.destroy(11);
-            assert_eq!(pool.size(), 0);
+            pool.take().await.unwrap();
         };
         tokio_run_async!(fut);
+        assert_eq!(2, polls.load(Ordering::SeqCst));
     }
 
     #[test]
-    fn constant_backoff_not_used_on_invalid_conn() {
-        use std::sync::{Arc,Mutex};
+    fn idle_timeout_discards_on_take() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .idle_timeout(Some(Duration::from_millis(50)))
+            .build();
 
-        let first_invoke = Arc::new(Mutex::new(None));
-        let second_invoke = Arc::new(Mutex::new(None));
+        take_now(&pool);
+        assert_eq!(1, pool.size());
+        runtime.advance(Duration::from_millis(49));
+        take_now(&pool);
+        assert_eq!(1, factory.calls());
 
-        let f = first_invoke.clone();
-        let s = second_invoke.clone();
+        runtime.advance(Duration::from_millis(50));
+        take_now(&pool);
+        assert_eq!(1, pool.size());
+        assert_eq!(2, factory.calls());
+    }
 
-        let pool = Pool::<TcpConn>::builder()
-            .factory(move || {
-                let mut f = f.lock().unwrap();
-                let mut s = s.lock().unwrap();
+    #[test]
+    fn maintenance_evicts_idle_objects() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .idle_timeout(Some(Duration::from_millis(50)))
+            .maintenance_interval(Duration::from_millis(20))
+            .build();
 
-                if f.is_none() {
-                    *f = Some(Instant::now());
-                    futures::future::ok(TcpConn(false))
-                } else {
-                    *s = Some(Instant::now());
-                    futures::future::ok(TcpConn(true))
+        let mut initialize = Box::pin(pool.initialize(3));
+        match poll_once(&mut initialize) {
+            Poll::Ready(report) => assert!(report.is_ok()),
+            Poll::Pending => panic!("should initialize right away"),
+        };
+
+        let mut maintenance = pool.maintenance();
+        assert!(poll_once(&mut maintenance).is_pending());
+        runtime.advance(Duration::from_millis(40));
+        assert!(poll_once(&mut maintenance).is_pending());
+        assert_eq!(3, pool.size());
+
+        runtime.advance(Duration::from_millis(20));
+        assert!(poll_once(&mut maintenance).is_pending());
+        assert_eq!(0, pool.size());
+    }
+
+    #[test]
+    fn maintenance_stops_when_pool_is_dropped() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime).build();
+
+        //join polls the maintenance first, it waits for its interval when the pool is dropped
+        let maintenance = pool.maintenance();
+        let drop_pool = async move { drop(pool) };
+        futures::executor::block_on(futures::future::join(maintenance, drop_pool));
+        assert_eq!(0, runtime.sleeping());
+    }
+
+    #[test]
+    fn max_lifetime_discards_on_return() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .max_lifetime(Some(Duration::from_millis(50)))
+            .build();
+
+        let object = take_now(&pool);
+        runtime.advance(Duration::from_millis(50));
+        drop(object);
+        assert_eq!(0, pool.size());
+    }
+
+    #[test]
+    fn max_lifetime_refuses_expired_objects() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .max_lifetime(Some(Duration::from_millis(50)))
+            .max_lifetime_jitter(Duration::from_millis(10))
+            .build();
+
+        take_now(&pool);
+        assert_eq!(1, pool.size());
+
+        runtime.advance(Duration::from_millis(50));
+        take_now(&pool);
+        assert_eq!(1, pool.size());
+        assert_eq!(2, factory.calls());
+    }
+
+    #[test]
+    fn maintenance_keeps_min_idle() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .min_idle(Some(3))
+            .maintenance_interval(Duration::from_millis(20))
+            .build();
+
+        let mut maintenance = pool.maintenance();
+        assert!(poll_once(&mut maintenance).is_pending());
+        assert_eq!(3, pool.size());
+
+        pool.try_take().unwrap().detach().unwrap();
+        pool.try_take().unwrap().detach().unwrap();
+        assert_eq!(1, pool.size());
+
+        runtime.advance(Duration::from_millis(20));
+        assert!(poll_once(&mut maintenance).is_pending());
+        assert_eq!(3, pool.size());
+        assert_eq!(5, factory.calls());
+    }
+
+    #[test]
+    fn maintenance_top_up_respects_max_size() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .min_idle(Some(3))
+            .max_size(Some(2))
+            .maintenance_interval(Duration::from_millis(20))
+            .build();
+
+        let mut maintenance = pool.maintenance();
+        assert!(poll_once(&mut maintenance).is_pending());
+        assert_eq!(2, pool.size());
+    }
+
+    #[test]
+    fn initialize_10_concurrently() {
+        let runtime = MockRuntime::new();
+        let rt = runtime.clone();
+        let pool = Pool::<MockObject>::builder()
+            .factory(move || {
+                let sleep = rt.sleep(Duration::from_millis(50));
+                async move {
+                    sleep.await;
+                    Ok(MockObject::new(0))
                 }
             })
-            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(100)))
+            .initialize_concurrency(Some(5))
+            .timeout(None)
+            .runtime(runtime.clone())
             .build();
 
-        let fut = async move {
-            pool.initialize(2).await.unwrap();
+        let mut initialize = Box::pin(pool.initialize(10));
+        assert!(poll_once(&mut initialize).is_pending());
+        assert_eq!(5, runtime.sleeping());
+        runtime.advance(Duration::from_millis(50));
+        assert!(poll_once(&mut initialize).is_pending());
+        assert_eq!(5, runtime.sleeping());
+        runtime.advance(Duration::from_millis(50));
+        let report = match poll_once(&mut initialize) {
+            Poll::Ready(report) => report,
+            Poll::Pending => panic!("two rounds of 5 should create 10 objects"),
         };
-        tokio_run_async!(fut);
-
-        let f = first_invoke.lock().unwrap();
-        let s = second_invoke.lock().unwrap();
-        let diff = s.unwrap().duration_since(f.unwrap());
-        assert!(diff.as_millis() < 100);
+        assert_eq!(10, report.created);
+        assert_eq!(10, pool.size());
     }
 
     #[test]
-    fn constant_backoff_100ms_on_error() {
-        use std::sync::{Arc,Mutex};
-
-        let first_invoke = Arc::new(Mutex::new(None));
-        let second_invoke = Arc::new(Mutex::new(None));
+    fn initialize_counts_only_new_objects() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime).build();
+        pool.put(MockObject::new(10));
+        pool.put(MockObject::new(11));
+
+        let report = match poll_once(&mut Box::pin(pool.initialize(3))) {
+            Poll::Ready(report) => report,
+            Poll::Pending => panic!("should initialize right away"),
+        };
+        assert!(report.is_ok());
+        assert_eq!(1, report.created);
+        assert_eq!(1, factory.calls());
+        assert_eq!(3, pool.size());
+    }
 
-        let f = first_invoke.clone();
-        let s = second_invoke.clone();
+    #[test]
+    fn initialize_10_reports_failures() {
+        use std::sync::{Arc, Mutex};
 
+        let c = Arc::new(Mutex::new(0));
+        let cc = c.clone();
         let pool = Pool::<TcpConnErr>::builder()
             .factory(move || {
-                let mut f = f.lock().unwrap();
-                let mut s = s.lock().unwrap();
-
-                if f.is_none() {
-                    *f = Some(Instant::now());
-                    futures::future::ok(TcpConnErr(Some(ErrorKind::AddrInUse)))
+                let mut cc = cc.lock().unwrap();
+                *cc = (*cc) + 1;
+                if *cc % 2 == 0 {
+                    futures::future::ok(TcpConnErr(Some(ErrorKind::BrokenPipe)))
                 } else {
-                    *s = Some(Instant::now());
                     futures::future::ok(TcpConnErr(None))
                 }
             })
-            .timeout(None)
-            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(100)))
+            .max_tries(Some(1))
             .build();
 
         let fut = async move {
-            pool.initialize(2).await.unwrap();
+            let report = pool.initialize(10).await;
+            assert_eq!(5, report.created);
+            assert_eq!(5, report.errors.len());
+            assert_eq!(5, pool.size());
         };
         tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn close_fails_take_and_discards_idle() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .build();
+
+        let fut = async move {
+            assert!(pool.initialize(3).await.is_ok());
+            pool.close();
+            assert_eq!(0, pool.size());
+            assert!(pool.is_closed());
 
-        let f = first_invoke.lock().unwrap();
-        let s = second_invoke.lock().unwrap();
-        let diff = s.unwrap().duration_since(f.unwrap());
-        assert!(diff.as_millis() >= 100);
+            match pool.take().await {
+                Ok(_) => panic!("should not work"),
+                Err(err) => assert!(err.is_closed()),
+            };
+        };
+        tokio_run_async!(fut);
     }
 
     #[test]
-    fn constant_backoff_50ms_on_error_fail() {
-        use std::sync::{Arc,Mutex};
+    fn close_wakes_waiting_takers() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .max_size(Some(1))
+            .timeout(None)
+            .build();
 
-        let first_invoke = Arc::new(Mutex::new(None));
-        let second_invoke = Arc::new(Mutex::new(None));
+        let object = take_now(&pool);
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        pool.close();
+        match poll_once(&mut take) {
+            Poll::Ready(Err(err)) => assert!(err.is_closed()),
+            _ => panic!("should not work"),
+        };
 
-        let f = first_invoke.clone();
-        let s = second_invoke.clone();
+        drop(object);
+        assert_eq!(0, pool.size());
+    }
 
-        let pool = Pool::<TcpConnErr>::builder()
-            .factory(move || {
-                let mut f = f.lock().unwrap();
-                let mut s = s.lock().unwrap();
+    #[test]
+    fn close_wakes_takers_in_flight() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory.script(MockCreate::Hang);
+        let pool = mock_builder(&factory, &runtime).timeout(None).build();
+
+        //join polls the take first, it waits for the factory when the pool is closed
+        let drain = async { pool.drain().await };
+        let (res, ()) = futures::executor::block_on(futures::future::join(pool.take(), drain));
+        match res {
+            Ok(_) => panic!("should not work"),
+            Err(err) => assert!(err.is_closed()),
+        };
+        assert_eq!(1, factory.calls());
+        assert_eq!(0, pool.live());
+    }
 
-                if f.is_none() {
-                    *f = Some(Instant::now());
-                    futures::future::ok(TcpConnErr(Some(ErrorKind::AddrInUse)))
-                } else {
-                    *s = Some(Instant::now());
-                    futures::future::ok(TcpConnErr(None))
+    #[test]
+    fn drain_waits_for_checked_out_objects() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime).build();
+
+        let object = take_now(&pool);
+        let mut drain = pool.drain();
+        assert!(poll_once(&mut drain).is_pending(), "the object is still out");
+
+        drop(object);
+        assert!(poll_once(&mut drain).is_ready());
+        assert_eq!(0, pool.size());
+    }
+
+    #[test]
+    fn destroyer_runs_for_discarded_objects() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let destroyed = Arc::new(AtomicUsize::new(0));
+        let d = destroyed.clone();
+        let pool = mock_builder(&factory, &runtime)
+            .destroyer(move |_| {
+                let d = d.clone();
+                async move {
+                    d.fetch_add(1, Ordering::SeqCst);
                 }
             })
-            .timeout(None)
-            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(50)))
+            .capacity(Some(2))
+            .build();
+
+        assert!(poll_once(&mut Box::pin(pool.initialize(2))).is_ready());
+        pool.put(MockObject::new(10)); //evicts one
+        pool.destroy(2);
+        let unusable = MockObject::new(11);
+        unusable.set_usable(false);
+        pool.put(unusable);
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending()); //the unusable one is discarded
+        assert!(poll_once(&mut take).is_ready());
+
+        //the destroyers run on the runtime
+        assert_eq!(0, destroyed.load(Ordering::SeqCst));
+        assert_eq!(0, runtime.run_spawned());
+        assert_eq!(4, destroyed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn destroyer_runs_for_idle_objects_of_a_dropped_pool() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let destroyed = Arc::new(AtomicUsize::new(0));
+        let d = destroyed.clone();
+        let pool = mock_builder(&factory, &runtime)
+            .destroyer(move |_| {
+                let d = d.clone();
+                async move {
+                    d.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .build();
+
+        assert!(poll_once(&mut Box::pin(pool.initialize(2))).is_ready());
+        drop(pool);
+        assert_eq!(0, runtime.run_spawned());
+        assert_eq!(2, destroyed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn recycle_failure_discards_the_object() {
+        let pool = Pool::<RecycleConn>::builder()
+            .factory(|| futures::future::ok(RecycleConn::default()))
+            .build();
+
+        let fut = async move {
+            pool.take().await.unwrap();
+            assert_eq!(1, pool.size());
+
+            let mut object = pool.take().await.unwrap();
+            object.fail = true;
+            drop(object);
+            assert_eq!(0, pool.size());
+        };
+        tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn pending_recycle_returns_the_object_later() {
+        let runtime = MockRuntime::new();
+        let pool = Pool::<RecycleConn>::builder()
+            .factory(|| futures::future::ok(RecycleConn::default()))
+            .runtime(runtime.clone())
+            .build();
+
+        let object = match poll_once(&mut Box::pin(pool.take())) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("should take an object right away"),
+        };
+        let recycle = object.recycle.clone();
+        recycle.hold();
+        drop(object);
+        assert_eq!(0, pool.size());
+        assert_eq!(1, runtime.run_spawned());
+        assert_eq!(0, pool.size());
+
+        recycle.release();
+        assert_eq!(0, runtime.run_spawned());
+        assert_eq!(1, pool.size());
+    }
+
+    #[test]
+    fn health_check_never() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(false)))
+            .health_check(HealthCheck::Never)
             .build();
 
         let fut = async move {
-            pool.initialize(2).await.unwrap();
+            pool.take().await.unwrap();
+            assert_eq!(HealthCheckStats::default(), pool.health_check_stats());
         };
         tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn health_check_on_checkin() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .health_check(HealthCheck::OnCheckin)
+            .build();
+
+        let fut = async move {
+            pool.take().await.unwrap();
+            assert_eq!(1, pool.size());
+
+            let mut object = pool.take().await.unwrap();
+            object.0 = false;
+            drop(object);
+            assert_eq!(0, pool.size());
 
-        let f = first_invoke.lock().unwrap();
-        let s = second_invoke.lock().unwrap();
-        let diff = s.unwrap().duration_since(f.unwrap());
-        assert!(diff.as_millis() < 200);
+            let stats = pool.health_check_stats();
+            assert_eq!(1, stats.passed);
+            assert_eq!(1, stats.unusable);
+        };
+        tokio_run_async!(fut);
     }
 
     #[test]
-    fn fail_after_3_max_tries() {
-        use std::sync::{Arc, Mutex};
+    fn health_check_idle_longer_than() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .health_check(HealthCheck::IdleLongerThan(Duration::from_millis(50)))
+            .build();
 
-        let c = Arc::new(Mutex::new(0));
-        let cc = c.clone();
+        take_now(&pool);
+        take_now(&pool);
+        assert_eq!(0, pool.health_check_stats().passed);
+
+        runtime.advance(Duration::from_millis(100));
+        take_now(&pool);
+        assert_eq!(1, pool.health_check_stats().passed);
+    }
 
+    #[test]
+    fn health_check_stats_count_failures() {
         let pool = Pool::<TcpConnErr>::builder()
-            .factory(move || {
-                let cc = cc.clone();
-                let mut cc = cc.lock().unwrap();
-                *cc = (*cc) + 1;
-                futures::future::ok(TcpConnErr(Some(ErrorKind::BrokenPipe)))
-            })
+            .factory(|| futures::future::ok(TcpConnErr(Some(ErrorKind::BrokenPipe))))
             .max_tries(Some(3))
             .build();
 
         let fut = async move {
-            match pool.take().await {
-                Ok(_) => panic!("should not work"),
-                Err(err) => assert_eq!(err.kind(), ErrorKind::BrokenPipe),
-            };
+            assert!(pool.take().await.is_err());
+            assert_eq!(3, pool.health_check_stats().failed);
         };
         tokio_run_async!(fut);
-        assert_eq!(3, *c.lock().unwrap());
     }
 
-    #[derive(Debug, Clone)]
-    struct TcpConnErr(Option<ErrorKind>);
+    #[test]
+    fn initialize_10() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .capacity(None)
+            .build();
 
-    impl PoolObject for TcpConnErr {
-        fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool>> {
-            match self.0 {
-                Some(err) => Poll::Ready(Err(Error::from(err))),
-                None => Poll::Ready(Ok(true)),
-            }
+        let fut = async move {
+            assert!(pool.initialize(10).await.is_ok());
+            assert_eq!(pool.size(), 10);
+        };
+        tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn initialize_10_capacity_8() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .capacity(Some(8))
+            .build();
+
+        let fut = async move {
+            assert!(pool.initialize(10).await.is_ok());
+            assert_eq!(pool.size(), 8);
+        };
+        tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn initialize_10_capacity_11() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .capacity(Some(11))
+            .build();
+
+        let fut = async move {
+            assert!(pool.initialize(10).await.is_ok());
+            assert_eq!(pool.size(), 10);
+        };
+        tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn initialize_10_destroy_3() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .build();
+
+        let fut = async move {
+            assert!(pool.initialize(10).await.is_ok());
+            pool.destroy(3);
+            assert_eq!(pool.size(), 7);
+        };
+        tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn initialize_10_destroy_11() {
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .build();
+
+        let fut = async move {
+            assert!(pool.initialize(10).await.is_ok());
+            pool.destroy(11);
+            assert_eq!(pool.size(), 0);
+        };
+        tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn constant_backoff_not_used_on_invalid_conn() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory.script(MockCreate::Unusable);
+        let pool = mock_builder(&factory, &runtime)
+            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(100)))
+            .build();
+
+        //the taker only yields to the executor before creating the next object,
+        //the take timeout is the only sleep
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!(1, runtime.sleeping());
+        assert!(poll_once(&mut take).is_ready());
+        assert_eq!(2, factory.calls());
+    }
+
+    #[test]
+    fn constant_backoff_100ms_on_error() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory.script(MockCreate::FailingTest(MockError("address in use".to_string())));
+        let pool = mock_builder(&factory, &runtime)
+            .timeout(None)
+            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(100)))
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(99));
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!(1, factory.calls());
+
+        runtime.advance(Duration::from_millis(1));
+        assert!(poll_once(&mut take).is_ready());
+        assert_eq!(2, factory.calls());
+    }
+
+    #[test]
+    fn constant_backoff_50ms_on_error_fail() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory
+            .script(MockCreate::FailingTest(MockError("address in use".to_string())))
+            .script(MockCreate::FailingTest(MockError("address in use".to_string())));
+        let pool = mock_builder(&factory, &runtime)
+            .timeout(None)
+            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(50)))
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(50));
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!(2, factory.calls());
+
+        runtime.advance(Duration::from_millis(50));
+        assert!(poll_once(&mut take).is_ready());
+        assert_eq!(3, factory.calls());
+    }
+
+    #[test]
+    fn fail_after_3_max_tries() {
+        use std::sync::{Arc, Mutex};
+
+        let c = Arc::new(Mutex::new(0));
+        let cc = c.clone();
+
+        let pool = Pool::<TcpConnErr>::builder()
+            .factory(move || {
+                let cc = cc.clone();
+                let mut cc = cc.lock().unwrap();
+                *cc = (*cc) + 1;
+                futures::future::ok(TcpConnErr(Some(ErrorKind::BrokenPipe)))
+            })
+            .max_tries(Some(3))
+            .build();
+
+        let fut = async move {
+            match pool.take().await {
+                Ok(_) => panic!("should not work"),
+                Err(err) => match err {
+                    crate::Error::MaxTriesExceeded { last_error, tries } => {
+                        assert_eq!(3, tries);
+                        match *last_error {
+                            crate::Error::Validation(err) => {
+                                assert_eq!(err.kind(), ErrorKind::BrokenPipe)
+                            }
+                            err => panic!("unexpected error {}", err),
+                        }
+                    }
+                    err => panic!("unexpected error {}", err),
+                },
+            };
+        };
+        tokio_run_async!(fut);
+        assert_eq!(3, *c.lock().unwrap());
+    }
+
+    #[test]
+    fn factory_errors_are_retried_with_backoff() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory
+            .script(MockCreate::Fail(MockError("connection refused".to_string())))
+            .script(MockCreate::Fail(MockError("connection refused".to_string())));
+        let pool = mock_builder(&factory, &runtime)
+            .connect_backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(50)))
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(50));
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(50));
+        assert!(poll_once(&mut take).is_ready());
+        assert_eq!(3, factory.calls());
+    }
+
+    #[test]
+    fn failing_factory_without_backoff_yields() {
+        use futures::task::noop_waker_ref;
+        use futures::FutureExt;
+
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::err(Error::from(ErrorKind::ConnectionRefused)))
+            .connect_max_tries(None)
+            .connect_backoff(BackoffStrategy::None)
+            .timeout(None)
+            .build();
+
+        //every retry gives the executor a turn instead of looping inside poll
+        let mut take = pool.take().boxed();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(take.poll_unpin(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn factory_errors_back_off_by_default() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory
+            .script(MockCreate::Fail(MockError("connection refused".to_string())))
+            .script(MockCreate::Fail(MockError("connection refused".to_string())));
+        let pool = mock_builder(&factory, &runtime).build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!(1, factory.calls());
+        runtime.advance(Duration::from_millis(10));
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!(2, factory.calls());
+        runtime.advance(Duration::from_millis(20));
+        assert!(poll_once(&mut take).is_ready());
+        assert_eq!(3, factory.calls());
+    }
+
+    #[test]
+    fn factory_fails_after_3_connect_max_tries() {
+        use std::sync::{Arc, Mutex};
+
+        let c = Arc::new(Mutex::new(0));
+        let cc = c.clone();
+        let pool = Pool::<TcpConn>::builder()
+            .factory(move || {
+                let mut cc = cc.lock().unwrap();
+                *cc = (*cc) + 1;
+                futures::future::err(Error::from(ErrorKind::ConnectionRefused))
+            })
+            .connect_max_tries(Some(3))
+            .build();
+
+        let fut = async move {
+            match pool.take().await {
+                Ok(_) => panic!("should not work"),
+                Err(err) => match err {
+                    crate::Error::MaxTriesExceeded { last_error, tries } => {
+                        assert_eq!(3, tries);
+                        match *last_error {
+                            crate::Error::Factory(err) => {
+                                assert_eq!(err.kind(), ErrorKind::ConnectionRefused)
+                            }
+                            err => panic!("unexpected error {}", err),
+                        }
+                    }
+                    err => panic!("unexpected error {}", err),
+                },
+            };
+        };
+        tokio_run_async!(fut);
+        assert_eq!(3, *c.lock().unwrap());
+    }
+
+    #[test]
+    fn domain_errors_are_returned_unchanged() {
+        let pool = Pool::<DbConn>::builder()
+            .factory(|| futures::future::err(DbError::AuthFailed("alice".to_string())))
+            .connect_max_tries(Some(1))
+            .build();
+
+        let fut = async move {
+            match pool.take().await {
+                Ok(_) => panic!("should not work"),
+                Err(err) => {
+                    assert_eq!(err.inner(), Some(&DbError::AuthFailed("alice".to_string())))
+                }
+            };
+        };
+        tokio_run_async!(fut);
+    }
+
+    #[test]
+    fn take_and_maintenance_are_send() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let pool = Pool::<TcpConn>::builder()
+            .factory(|| futures::future::ok(TcpConn(true)))
+            .build();
+        assert_send(&pool.take());
+        assert_send(&pool.maintenance());
+    }
+
+    #[test]
+    fn custom_runtime_drives_backoff_and_teardown() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let runtime = MockRuntime::new();
+        let destroyed = Arc::new(AtomicUsize::new(0));
+        let d = destroyed.clone();
+        let pool = Pool::<TcpConnErr>::builder()
+            .factory(|| futures::future::ok(TcpConnErr(Some(ErrorKind::BrokenPipe))))
+            .destroyer(move |_| {
+                d.fetch_add(1, Ordering::SeqCst);
+                futures::future::ready(())
+            })
+            .max_tries(Some(3))
+            .backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(50)))
+            .runtime(runtime.clone())
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.run_spawned();
+        assert_eq!(1, destroyed.load(Ordering::SeqCst));
+
+        runtime.advance(Duration::from_millis(49));
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(1));
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(50));
+        match poll_once(&mut take) {
+            Poll::Ready(Err(crate::Error::MaxTriesExceeded { tries, .. })) => assert_eq!(3, tries),
+            _ => panic!("should fail after 3 tries"),
+        }
+
+        runtime.run_spawned();
+        assert_eq!(3, destroyed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn default_runtime_works_outside_tokio() {
+        let pool = Pool::<MockObject>::builder()
+            .factory(|| futures::future::ok(MockObject::new(1)))
+            .max_size(Some(1))
+            .timeout(Some(Duration::from_millis(50)))
+            .build();
+
+        futures::executor::block_on(async move {
+            let object = pool.take().await.unwrap();
+            let started_at = Instant::now();
+            assert!(pool.take().await.is_err());
+            //the sleep must not resolve early without a tokio timer
+            assert!(started_at.elapsed() >= Duration::from_millis(50));
+            drop(object);
+            assert!(pool.take().await.is_ok());
+        });
+    }
+
+    #[test]
+    fn default_runtime_spawns_on_a_shared_thread_pool_outside_tokio() {
+        use futures::channel::mpsc;
+        use futures::StreamExt;
+
+        let (sender, mut threads) = mpsc::unbounded();
+        let pool = Pool::<MockObject>::builder()
+            .factory(|| futures::future::ok(MockObject::new(1)))
+            .destroyer(move |_| {
+                let sender = sender.clone();
+                async move {
+                    let name = std::thread::current().name().map(str::to_string);
+                    sender.unbounded_send(name).unwrap();
+                }
+            })
+            .build();
+
+        futures::executor::block_on(async move {
+            let objects = vec![pool.take().await.unwrap(), pool.take().await.unwrap()];
+            for mut object in objects {
+                object.invalidate();
+            }
+            for _ in 0..2 {
+                let name = threads.next().await.unwrap().unwrap();
+                assert!(name.starts_with("fut-pool-"));
+            }
+        });
+    }
+
+    #[test]
+    fn local_pool_reuses_rc_objects() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let created = Rc::new(Cell::new(0));
+        let c = created.clone();
+        let pool = LocalPool::<RcConn>::builder()
+            .factory(move || {
+                c.set(c.get() + 1);
+                futures::future::ok(RcConn(c.clone()))
+            })
+            .build();
+
+        futures::executor::block_on(async move {
+            let object = pool.take().await.unwrap();
+            assert_eq!(1, object.0.get());
+            drop(object);
+            assert_eq!(1, pool.size());
+
+            pool.take().await.unwrap();
+            assert_eq!(1, pool.size());
+        });
+        assert_eq!(1, created.get());
+    }
+
+    #[test]
+    fn local_pool_max_size_waits_for_return() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let pool = LocalPool::<RcConn>::builder()
+            .factory(|| futures::future::ok(RcConn(Rc::new(Cell::new(0)))))
+            .max_size(Some(1))
+            .timeout(Some(Duration::from_millis(50)))
+            .build();
+
+        futures::executor::block_on(async move {
+            let object = pool.take().await.unwrap();
+            match pool.take().await {
+                Ok(_) => panic!("should not work"),
+                Err(err) => match err {
+                    crate::Error::Timeout { phase } => assert_eq!(phase, Phase::Waiting),
+                    err => panic!("unexpected error {}", err),
+                },
+            };
+
+            let (first, _) = futures::future::join(pool.take(), async move {
+                drop(object);
+            })
+            .await;
+            assert!(first.is_ok());
+        });
+    }
+
+    #[test]
+    fn local_pool_waiters_are_served_in_order() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let pool = LocalPool::<RcConn>::builder()
+            .factory(|| futures::future::ok(RcConn(Rc::new(Cell::new(0)))))
+            .max_size(Some(1))
+            .timeout(None)
+            .build();
+
+        let object = match poll_once(&mut Box::pin(pool.take())) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("should take an object right away"),
+        };
+        object.0.set(1);
+
+        let mut first = Box::pin(pool.take());
+        let mut second = Box::pin(pool.take());
+        assert!(poll_once(&mut first).is_pending());
+        assert!(poll_once(&mut second).is_pending());
+
+        drop(object);
+        assert!(poll_once(&mut second).is_pending());
+        let object = match poll_once(&mut first) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("the oldest take should get the object"),
+        };
+        assert_eq!(1, object.0.get());
+
+        drop(object);
+        assert!(poll_once(&mut second).is_ready());
+    }
+
+    #[test]
+    fn pool_state_counts_objects_and_waiters() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory.script(MockCreate::Object).script(MockCreate::Hang);
+        let pool = mock_builder(&factory, &runtime)
+            .max_size(Some(2))
+            .health_check(HealthCheck::Never)
+            .build();
+
+        let object = take_now(&pool);
+        let mut creating = Box::pin(pool.take());
+        assert!(poll_once(&mut creating).is_pending());
+        let mut waiting = Box::pin(pool.take());
+        assert!(poll_once(&mut waiting).is_pending());
+        let state = pool.state();
+        assert_eq!((0, 1, 1, 1), (state.idle, state.in_use, state.pending, state.waiters));
+
+        drop(waiting);
+        drop(creating);
+        drop(object);
+        assert_eq!(
+            PoolState {
+                idle: 1,
+                in_use: 0,
+                pending: 0,
+                waiters: 0,
+            },
+            pool.state()
+        );
+    }
+
+    #[test]
+    fn pool_metrics_count_lifecycle_and_timeouts() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let pool = mock_builder(&factory, &runtime)
+            .max_size(Some(1))
+            .max_lifetime(Some(Duration::from_millis(50)))
+            .timeout(Some(Duration::from_millis(20)))
+            .health_check(HealthCheck::Never)
+            .build();
+
+        let object = take_now(&pool);
+        let mut waiting = Box::pin(pool.take());
+        assert!(poll_once(&mut waiting).is_pending());
+        runtime.advance(Duration::from_millis(20));
+        assert!(poll_once(&mut waiting).is_ready());
+        drop(object);
+
+        //the idle object outlived max_lifetime and is replaced
+        runtime.advance(Duration::from_millis(30));
+        take_now(&pool);
+
+        let metrics = pool.metrics();
+        assert_eq!(2, metrics.created);
+        assert_eq!(1, metrics.destroyed);
+        assert_eq!(1, metrics.acquire_timeouts);
+        assert_eq!(0, metrics.failed_health_checks);
+        assert_eq!(2, metrics.wait_time.count());
+        assert_eq!(Some((Some(Duration::from_millis(1)), 2)), metrics.wait_time.buckets().next());
+    }
+
+    #[test]
+    fn event_listener_sees_the_lifecycle() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory
+            .script(MockCreate::Fail(MockError("refused".to_string())))
+            .script(MockCreate::Object);
+        let events = EventLog::default();
+        let pool = mock_builder(&factory, &runtime)
+            .capacity(Some(1))
+            .timeout(Some(Duration::from_millis(100)))
+            .connect_backoff(BackoffStrategy::Fixed(FixedIntervalBackoff::from_millis(10)))
+            .event_listener(events.clone())
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(10));
+        let object = match poll_once(&mut take) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("should take an object after the backoff"),
+        };
+        pool.put(MockObject::new(9));
+        drop(object);
+        let _object = take_now(&pool);
+
+        factory.script(MockCreate::Hang);
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(100));
+        assert!(poll_once(&mut take).is_ready());
+
+        assert_eq!(
+            vec![
+                "factory start",
+                "factory finish 0ns Some(\"refused\")",
+                "backoff 10ms 1",
+                "factory start",
+                "factory finish 0ns None",
+                "health check Ok(true)",
+                "checkout 10ms 0 1",
+                "checkin",
+                "evict",
+                "health check Ok(true)",
+                "checkout 0ns 0 0",
+                "factory start",
+                "timeout connecting 100ms",
+            ],
+            events.take()
+        );
+    }
+
+    fn mock_keyed_pool(runtime: &MockRuntime, max_size: Option<usize>) -> KeyedPool<usize, MockObject> {
+        let runtime = runtime.clone();
+        KeyedPool::builder()
+            .factory(|key: &usize| futures::future::ok(MockObject::new(*key)))
+            .partition(move |builder| {
+                builder
+                    .runtime(runtime.clone())
+                    .health_check(HealthCheck::Never)
+            })
+            .max_size(max_size)
+            .build()
+    }
+
+    #[test]
+    fn keyed_pool_returns_objects_to_their_partition() {
+        let runtime = MockRuntime::new();
+        let pool = mock_keyed_pool(&runtime, None);
+
+        let first = take_now(&pool.partition(&1));
+        let second = match poll_once(&mut pool.take(&2)) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("should take an object right away"),
+        };
+        assert_eq!((1, 2), (first.id(), second.id()));
+        drop(first);
+        drop(second);
+
+        assert_eq!(1, pool.partition(&1).size());
+        assert_eq!(1, pool.partition(&2).size());
+        assert_eq!(2, pool.try_take(&2).unwrap().id());
+        let mut keys = pool.keys();
+        keys.sort();
+        assert_eq!(vec![1, 2], keys);
+    }
+
+    #[test]
+    fn keyed_pool_max_size_evicts_idle_objects_of_other_keys() {
+        let runtime = MockRuntime::new();
+        let pool = mock_keyed_pool(&runtime, Some(2));
+
+        let first = take_now(&pool.partition(&1));
+        drop(take_now(&pool.partition(&2)));
+        let third = take_now(&pool.partition(&3));
+        assert_eq!(0, pool.partition(&2).size());
+        assert_eq!(1, pool.partition(&2).metrics().destroyed);
+
+        let mut fourth = pool.take(&4);
+        assert!(poll_once(&mut fourth).is_pending());
+        drop(first);
+        match poll_once(&mut fourth) {
+            Poll::Ready(Ok(object)) => assert_eq!(4, object.id()),
+            _ => panic!("should evict the idle object of the first key"),
+        };
+        assert_eq!(0, pool.partition(&1).size());
+        drop(third);
+    }
+
+    #[test]
+    fn keyed_pool_max_size_serves_waiting_keys_in_order() {
+        let runtime = MockRuntime::new();
+        let pool = mock_keyed_pool(&runtime, Some(1));
+
+        let object = take_now(&pool.partition(&1));
+        let mut second = pool.take(&2);
+        let mut third = pool.take(&3);
+        assert!(poll_once(&mut second).is_pending());
+        assert!(poll_once(&mut third).is_pending());
+
+        drop(object);
+        assert!(poll_once(&mut third).is_pending());
+        let object = match poll_once(&mut second) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("the oldest take should get the freed slot"),
+        };
+        assert_eq!(2, object.id());
+        assert_eq!(0, pool.partition(&1).size());
+
+        drop(object);
+        match poll_once(&mut third) {
+            Poll::Ready(Ok(object)) => assert_eq!(3, object.id()),
+            _ => panic!("the next take should get the slot"),
+        };
+    }
+
+    #[test]
+    fn keyed_pool_max_size_hands_objects_to_waiting_takes_of_the_same_key() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let runtime = MockRuntime::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let pool = KeyedPool::builder()
+            .factory(move |key: &usize| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                futures::future::ok(MockObject::new(*key))
+            })
+            .partition(move |builder| {
+                builder
+                    .runtime(runtime.clone())
+                    .health_check(HealthCheck::Never)
+            })
+            .max_size(Some(1))
+            .build();
+
+        let object = take_now(&pool.partition(&1));
+        let mut second = pool.take(&1);
+        assert!(poll_once(&mut second).is_pending());
+        drop(object);
+        match poll_once(&mut second) {
+            Poll::Ready(Ok(object)) => assert_eq!(1, object.id()),
+            _ => panic!("the returned object should go to the waiting take"),
+        };
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert_eq!(0, pool.partition(&1).metrics().destroyed);
+    }
+
+    fn take_balanced(pool: &BalancedPool<MockObject>) -> Poll<std::result::Result<PoolGuard<MockObject>, crate::Error<MockError>>> {
+        poll_once(&mut Box::pin(pool.take()))
+    }
+
+    #[test]
+    fn balanced_pool_fails_over_and_probes_unhealthy_endpoints() {
+        let runtime = MockRuntime::new();
+        let failing = MockFactory::new();
+        failing
+            .script(MockCreate::Fail(MockError("refused".to_string())))
+            .script(MockCreate::Fail(MockError("refused".to_string())));
+        let healthy = MockFactory::new();
+        let pool = BalancedPool::builder()
+            .endpoint(mock_builder(&failing, &runtime).connect_max_tries(Some(1)))
+            .endpoint(mock_builder(&healthy, &runtime))
+            .failure_threshold(2)
+            .probe_interval(Duration::from_secs(1))
+            .build();
+
+        //both takes try the failing endpoint first and fail over
+        assert!(take_balanced(&pool).is_ready());
+        assert!(pool.is_healthy(0));
+        assert!(take_balanced(&pool).is_ready());
+        assert!(!pool.is_healthy(0));
+        assert_eq!(2, failing.calls());
+
+        //the probe interval counts from the failure, not from the next pick
+        runtime.advance(Duration::from_millis(600));
+        assert!(take_balanced(&pool).is_ready());
+        assert_eq!(2, failing.calls());
+
+        //the probe is a take of its own, the user's take goes to the healthy endpoint
+        runtime.advance(Duration::from_millis(400));
+        assert!(take_balanced(&pool).is_ready());
+        assert_eq!((2, 1), (failing.calls(), healthy.calls()));
+        assert_eq!(0, runtime.run_spawned());
+        assert_eq!(3, failing.calls());
+        assert!(pool.is_healthy(0));
+    }
+
+    #[test]
+    fn balanced_pool_timeout_bounds_failover() {
+        let runtime = MockRuntime::new();
+        let first = MockFactory::new();
+        first.script(MockCreate::Hang);
+        let second = MockFactory::new();
+        second.script(MockCreate::Hang);
+        let pool = BalancedPool::builder()
+            .endpoint(mock_builder(&first, &runtime).timeout(Some(Duration::from_millis(100))))
+            .endpoint(mock_builder(&second, &runtime).timeout(Some(Duration::from_millis(100))))
+            .timeout(Some(Duration::from_millis(150)))
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        runtime.advance(Duration::from_millis(100));
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!((1, 1), (first.calls(), second.calls()));
+
+        runtime.advance(Duration::from_millis(50));
+        match poll_once(&mut take) {
+            Poll::Ready(Err(crate::Error::Timeout { phase })) => {
+                assert_eq!(Phase::Connecting, phase)
+            }
+            _ => panic!("the whole take should time out"),
+        };
+    }
+
+    #[test]
+    fn balanced_pool_splits_timeout_among_endpoints() {
+        let runtime = MockRuntime::new();
+        let first = MockFactory::new();
+        first.script(MockCreate::Hang);
+        let second = MockFactory::new();
+        second.script(MockCreate::Hang);
+        let pool = BalancedPool::builder()
+            .endpoint(mock_builder(&first, &runtime).timeout(None))
+            .endpoint(mock_builder(&second, &runtime).timeout(None))
+            .failure_threshold(1)
+            .timeout(Some(Duration::from_millis(200)))
+            .build();
+
+        let mut take = Box::pin(pool.take());
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!((1, 0), (first.calls(), second.calls()));
+
+        //the hanging endpoint used up its share and counts as failed
+        runtime.advance(Duration::from_millis(100));
+        assert!(poll_once(&mut take).is_pending());
+        assert_eq!((1, 1), (first.calls(), second.calls()));
+        assert!(!pool.is_healthy(0));
+
+        runtime.advance(Duration::from_millis(100));
+        match poll_once(&mut take) {
+            Poll::Ready(Err(crate::Error::Timeout { phase })) => {
+                assert_eq!(Phase::Connecting, phase)
+            }
+            _ => panic!("the whole take should time out"),
+        };
+        assert!(!pool.is_healthy(1));
+    }
+
+    #[test]
+    fn balanced_pool_round_robin() {
+        let runtime = MockRuntime::new();
+        let first = MockFactory::new();
+        let second = MockFactory::new();
+        let pool = BalancedPool::builder()
+            .endpoint(mock_builder(&first, &runtime))
+            .endpoint(mock_builder(&second, &runtime))
+            .strategy(BalanceStrategy::RoundRobin)
+            .build();
+
+        let mut objects = Vec::new();
+        let mut calls = Vec::new();
+        for _ in 0..4 {
+            match take_balanced(&pool) {
+                Poll::Ready(Ok(object)) => objects.push(object),
+                _ => panic!("should take an object right away"),
+            }
+            calls.push((first.calls(), second.calls()));
+        }
+        assert_eq!(vec![(1, 0), (1, 1), (2, 1), (2, 2)], calls);
+    }
+
+    #[test]
+    fn balanced_pool_power_of_two_choices() {
+        let runtime = MockRuntime::new();
+        let busy = MockFactory::new();
+        let first = MockFactory::new();
+        let second = MockFactory::new();
+        let pool = BalancedPool::builder()
+            .endpoint(mock_builder(&busy, &runtime))
+            .endpoint(mock_builder(&first, &runtime))
+            .endpoint(mock_builder(&second, &runtime))
+            .strategy(BalanceStrategy::PowerOfTwoChoices)
+            .build();
+
+        let busy_pool = pool.endpoints()[0].clone();
+        let mut objects = vec![take_now(&busy_pool), take_now(&busy_pool)];
+        //whichever two endpoints are compared, the busy one loses
+        for _ in 0..2 {
+            match take_balanced(&pool) {
+                Poll::Ready(Ok(object)) => objects.push(object),
+                _ => panic!("should take an object right away"),
+            }
+            assert_eq!(2, busy.calls());
+        }
+        assert_eq!(2, first.calls() + second.calls());
+    }
+
+    #[test]
+    fn balanced_pool_least_in_use() {
+        let runtime = MockRuntime::new();
+        let first = MockFactory::new();
+        let second = MockFactory::new();
+        let pool = BalancedPool::builder()
+            .endpoint(mock_builder(&first, &runtime))
+            .endpoint(mock_builder(&second, &runtime))
+            .strategy(BalanceStrategy::LeastInUse)
+            .build();
+
+        let mut objects = Vec::new();
+        for _ in 0..3 {
+            match take_balanced(&pool) {
+                Poll::Ready(Ok(object)) => objects.push(object),
+                _ => panic!("should take an object right away"),
+            }
+        }
+        assert_eq!((2, 1), (first.calls(), second.calls()));
+        assert_eq!(2, pool.endpoints()[0].state().in_use);
+    }
+
+    #[test]
+    fn circuit_breaker_fails_fast_and_probes() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        for _ in 0..3 {
+            factory.script(MockCreate::Fail(MockError("refused".to_string())));
+        }
+        factory.script(MockCreate::Hang);
+        let pool = mock_builder(&factory, &runtime)
+            .connect_max_tries(Some(1))
+            .timeout(None)
+            .circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(1)))
+            .build();
+
+        for _ in 0..2 {
+            match poll_once(&mut Box::pin(pool.take())) {
+                Poll::Ready(Err(crate::Error::MaxTriesExceeded { .. })) => {}
+                _ => panic!("the factory should fail"),
+            };
+        }
+        assert_eq!(Some(CircuitState::Open), pool.circuit_state());
+        match poll_once(&mut Box::pin(pool.take())) {
+            Poll::Ready(Err(err)) => assert!(err.is_circuit_open()),
+            _ => panic!("should fail fast"),
+        };
+        assert_eq!(2, factory.calls());
+        assert_eq!(0, pool.live());
+
+        //a failing probe opens the breaker again
+        runtime.advance(Duration::from_secs(1));
+        assert_eq!(Some(CircuitState::HalfOpen), pool.circuit_state());
+        assert!(poll_once(&mut Box::pin(pool.take())).is_ready());
+        assert_eq!(Some(CircuitState::Open), pool.circuit_state());
+
+        //only one probe at a time, the others fail fast
+        runtime.advance(Duration::from_secs(1));
+        let mut probe = Box::pin(pool.take());
+        assert!(poll_once(&mut probe).is_pending());
+        match poll_once(&mut Box::pin(pool.take())) {
+            Poll::Ready(Err(err)) => assert!(err.is_circuit_open()),
+            _ => panic!("should fail fast while the probe runs"),
+        };
+        drop(probe);
+
+        match poll_once(&mut Box::pin(pool.take())) {
+            Poll::Ready(Ok(_)) => {}
+            _ => panic!("the probe should succeed"),
+        };
+        assert_eq!(Some(CircuitState::Closed), pool.circuit_state());
+        assert_eq!(5, factory.calls());
+    }
+
+    #[test]
+    fn circuit_breaker_counts_hanging_factory_calls_as_failures() {
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        factory.script(MockCreate::Hang).script(MockCreate::Hang);
+        let pool = mock_builder(&factory, &runtime)
+            .timeout(Some(Duration::from_millis(100)))
+            .circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(1)))
+            .build();
+
+        for _ in 0..2 {
+            let mut take = Box::pin(pool.take());
+            assert!(poll_once(&mut take).is_pending());
+            runtime.advance(Duration::from_millis(100));
+            match poll_once(&mut take) {
+                Poll::Ready(Err(crate::Error::Timeout { phase })) => {
+                    assert_eq!(Phase::Connecting, phase)
+                }
+                _ => panic!("the take should time out"),
+            };
+        }
+        assert_eq!(Some(CircuitState::Open), pool.circuit_state());
+        assert_eq!(2, factory.calls());
+    }
+
+    #[test]
+    fn mapped_guard_returns_the_object() {
+        fn assert_send<S: Send + 'static>(_: &S) {}
+
+        let pool = Pool::<CacheConn>::builder()
+            .factory(|| futures::future::ok(CacheConn { statements: Vec::new() }))
+            .runtime(MockRuntime::new())
+            .build();
+        let take = || match poll_once(&mut Box::pin(pool.take())) {
+            Poll::Ready(Ok(object)) => object,
+            _ => panic!("should take an object right away"),
+        };
+
+        let mut statements = PoolGuard::map(take(), |conn| &mut conn.statements);
+        statements.push("select 1".to_string());
+        let mut first = MappedPoolGuard::map(statements, |statements| &mut statements[0]);
+        first.push_str(" from dual");
+        assert_send(&first);
+        drop(first);
+        assert_eq!(1, pool.size());
+
+        let object = match PoolGuard::try_map(take(), |conn| conn.statements.get_mut(1)) {
+            Ok(_) => panic!("there is a single statement"),
+            Err(object) => object,
+        };
+        assert_eq!(vec!["select 1 from dual".to_string()], object.statements);
+        let statement = match PoolGuard::try_map(object, |conn| conn.statements.first_mut()) {
+            Ok(statement) => statement,
+            Err(_) => panic!("there is a statement"),
+        };
+        assert_eq!("select 1 from dual", &*statement);
+        assert_eq!(0, pool.size());
+        drop(statement);
+        assert_eq!(1, pool.size());
+    }
+
+    #[test]
+    fn invalidated_guard_is_discarded_and_frees_its_slot() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let runtime = MockRuntime::new();
+        let factory = MockFactory::new();
+        let destroyed = Arc::new(AtomicUsize::new(0));
+        let counter = destroyed.clone();
+        let pool = mock_builder(&factory, &runtime)
+            .max_size(Some(1))
+            .destroyer(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                futures::future::ready(())
+            })
+            .build();
+
+        let mut object = take_now(&pool);
+        let mut waiting = Box::pin(pool.take());
+        assert!(poll_once(&mut waiting).is_pending());
+
+        object.invalidate();
+        drop(object);
+        runtime.run_spawned();
+        assert_eq!(1, destroyed.load(Ordering::SeqCst));
+        assert_eq!(1, pool.metrics().destroyed);
+
+        //the waiting take got the slot and created a replacement
+        match poll_once(&mut waiting) {
+            Poll::Ready(Ok(object)) => assert_eq!(2, object.id()),
+            _ => panic!("should create a new object"),
+        };
+        assert_eq!(1, pool.size());
+
+        let mut object = PoolGuard::map(take_now(&pool), |object| object);
+        MappedPoolGuard::invalidate(&mut object);
+        drop(object);
+        assert_eq!(0, pool.size());
+        assert_eq!(2, pool.metrics().destroyed);
+    }
+
+    #[derive(Debug, Clone)]
+    struct TcpConnErr(Option<ErrorKind>);
+
+    impl PoolObject for TcpConnErr {
+        type Error = Error;
+
+        fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool>> {
+            match self.0 {
+                Some(err) => Poll::Ready(Err(Error::from(err))),
+                None => Poll::Ready(Ok(true)),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct SlowConn(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl PoolObject for SlowConn {
+        type Error = Error;
+
+        fn test_poll(&mut self, cx: &mut Context) -> Poll<Result<bool>> {
+            use std::sync::atomic::Ordering;
+
+            if self.0.fetch_add(1, Ordering::SeqCst) > 0 {
+                return Poll::Ready(Ok(true));
+            }
+
+            let waker = cx.waker().clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                waker.wake();
+            });
+            Poll::Pending
+        }
+    }
+
+    /// Holds the recycling of a `RecycleConn` until the test releases it.
+    #[derive(Debug, Default)]
+    struct RecycleGate {
+        held: std::sync::atomic::AtomicBool,
+        waker: parking_lot::Mutex<Option<std::task::Waker>>,
+    }
+
+    impl RecycleGate {
+        fn hold(&self) {
+            self.held.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn release(&self) {
+            self.held.store(false, std::sync::atomic::Ordering::SeqCst);
+            if let Some(waker) = self.waker.lock().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct RecycleConn {
+        fail: bool,
+        recycle: std::sync::Arc<RecycleGate>,
+    }
+
+    impl PoolObject for RecycleConn {
+        type Error = Error;
+
+        fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool>> {
+            Poll::Ready(Ok(true))
+        }
+
+        fn recycle_poll(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+            if self.recycle.held.load(std::sync::atomic::Ordering::SeqCst) {
+                *self.recycle.waker.lock() = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            if self.fail {
+                Poll::Ready(Err(Error::from(ErrorKind::InvalidData)))
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+    #[derive(Debug, PartialEq)]
+    enum DbError {
+        AuthFailed(String),
+    }
+
+    impl std::fmt::Display for DbError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                DbError::AuthFailed(user) => write!(f, "authentication failed for {}", user),
+            }
+        }
+    }
+
+    impl std::error::Error for DbError {}
+
+    struct DbConn;
+
+    impl PoolObject for DbConn {
+        type Error = DbError;
+
+        fn test_poll(&mut self, _: &mut Context) -> Poll<std::result::Result<bool, DbError>> {
+            Poll::Ready(Ok(true))
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct EventLog(std::sync::Arc<parking_lot::Mutex<Vec<String>>>);
+
+    impl EventLog {
+        fn push(&self, event: String) {
+            self.0.lock().push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::replace(&mut *self.0.lock(), Vec::new())
+        }
+    }
+
+    impl PoolEventListener for EventLog {
+        fn on_factory_start(&self) {
+            self.push("factory start".to_string());
+        }
+
+        fn on_factory_finish(&self, elapsed: Duration, error: Option<&(dyn StdError + 'static)>) {
+            let error = error.map(|err| err.to_string());
+            self.push(format!("factory finish {:?} {:?}", elapsed, error));
+        }
+
+        fn on_health_check(&self, result: std::result::Result<bool, &(dyn StdError + 'static)>) {
+            let result = result.map_err(|err| err.to_string());
+            self.push(format!("health check {:?}", result));
+        }
+
+        fn on_checkout(&self, progress: TakeProgress) {
+            self.push(format!(
+                "checkout {:?} {} {}",
+                progress.elapsed, progress.tries, progress.connect_tries
+            ));
+        }
+
+        fn on_checkin(&self) {
+            self.push("checkin".to_string());
+        }
+
+        fn on_evict(&self) {
+            self.push("evict".to_string());
+        }
+
+        fn on_backoff(&self, delay: Duration, progress: TakeProgress) {
+            self.push(format!("backoff {:?} {}", delay, progress.connect_tries));
+        }
+
+        fn on_acquire_timeout(&self, phase: Phase, progress: TakeProgress) {
+            self.push(format!("timeout {} {:?}", phase, progress.elapsed));
+        }
+    }
+
+    struct CacheConn {
+        statements: Vec<String>,
+    }
+
+    impl PoolObject for CacheConn {
+        type Error = Error;
+
+        fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool>> {
+            Poll::Ready(Ok(true))
+        }
+    }
+
+    //not Send, only usable with LocalPool
+    struct RcConn(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl PoolObject for RcConn {
+        type Error = Error;
+
+        fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool>> {
+            Poll::Ready(Ok(true))
         }
     }
 }
@@ -0,0 +1,515 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::mem;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::Context;
+use std::time::Duration;
+
+use futures::channel::oneshot::{Canceled, Receiver};
+use futures::future::{self, Either};
+use futures::task::noop_waker_ref;
+use futures::{Future, FutureExt, Poll};
+
+use crate::backoff::{BackoffStrategy, ExponentialBackoff};
+use crate::error::{Error, Phase};
+use crate::factory::LocalObjectFactory;
+use crate::object::PoolObject;
+use crate::runtime::{default_runtime, Runtime};
+use crate::waiters::{Handoff, Waiters};
+
+/// A pool for `!Send` objects on a single threaded executor.
+///
+/// It covers the basics of `Pool` - `timeout`, `max_tries`, `capacity`, `max_size` and
+/// backoff - and always runs `test_poll` on checkout. Since nothing can be spawned,
+/// `recycle_poll` has to complete right away, objects still recycling are discarded.
+/// Waiting takes are served in order, like the ones of `Pool`.
+pub struct LocalPool<T>
+where
+    T: PoolObject + 'static,
+{
+    inner: Rc<LocalPoolInner<T>>,
+}
+
+struct LocalPoolInner<T>
+where
+    T: PoolObject + 'static,
+{
+    factory: Box<LocalObjectFactory<T>>,
+    objects: RefCell<VecDeque<T>>,
+    timeout: Option<Duration>,
+    max_tries: Option<usize>,
+    connect_max_tries: Option<usize>,
+    capacity: Option<usize>,
+    max_size: Option<usize>,
+    backoff: BackoffStrategy,
+    connect_backoff: BackoffStrategy,
+    runtime: Arc<dyn Runtime>,
+    //idle + checked out + being created
+    live: Cell<usize>,
+    //takes waiting for an object or a free slot, oldest first
+    waiters: RefCell<Waiters<T>>,
+}
+
+enum LocalAcquire<T> {
+    Object(T),
+    Reserved,
+    Waiting(Receiver<Handoff<T>>),
+}
+
+/// A take queued for an object or a slot. If it's dropped, e.g. on timeout, whatever
+/// was handed to it in the meantime goes back to the pool.
+struct LocalWaiter<'a, T>
+where
+    T: PoolObject + 'static,
+{
+    pool: &'a LocalPool<T>,
+    receiver: Receiver<Handoff<T>>,
+}
+
+impl<'a, T> Future for LocalWaiter<'a, T>
+where
+    T: PoolObject + 'static,
+{
+    type Output = Result<Handoff<T>, Canceled>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.receiver.poll_unpin(cx)
+    }
+}
+
+impl<'a, T> Drop for LocalWaiter<'a, T>
+where
+    T: PoolObject + 'static,
+{
+    fn drop(&mut self) {
+        self.receiver.close();
+        match self.receiver.try_recv() {
+            Ok(Some(Handoff::Object(object))) => self.pool.put_idle(object),
+            Ok(Some(Handoff::Slot)) => self.pool.release(),
+            _ => {}
+        }
+    }
+}
+
+/// Gives a reserved slot back unless the factory produced an object for it.
+struct Reservation<'a, T>
+where
+    T: PoolObject + 'static,
+{
+    pool: &'a LocalPool<T>,
+}
+
+impl<'a, T> Drop for Reservation<'a, T>
+where
+    T: PoolObject + 'static,
+{
+    fn drop(&mut self) {
+        self.pool.release();
+    }
+}
+
+impl<T> Clone for LocalPool<T>
+where
+    T: PoolObject + 'static,
+{
+    fn clone(&self) -> Self {
+        LocalPool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> LocalPool<T>
+where
+    T: PoolObject + 'static,
+{
+    pub fn builder() -> LocalPoolBuilder<T> {
+        LocalPoolBuilder::new()
+    }
+
+    pub async fn take(&self) -> Result<LocalPoolGuard<T>, Error<T::Error>> {
+        let phase = Cell::new(Phase::Waiting);
+        let take = Box::pin(self.take_object(&phase));
+        let deadline = match self.inner.timeout {
+            Some(timeout) => self.inner.runtime.sleep(timeout),
+            None => return take.await,
+        };
+
+        let res = future::select(take, deadline).await;
+        match res {
+            Either::Left((res, _)) => res,
+            Either::Right(_) => {
+                debug!("local pool timed out while {}", phase.get());
+                Err(Error::Timeout { phase: phase.get() })
+            }
+        }
+    }
+
+    pub fn try_take(&self) -> Option<LocalPoolGuard<T>> {
+        let object = self.inner.objects.borrow_mut().pop_front()?;
+        Some(LocalPoolGuard::new(object, self.clone()))
+    }
+
+    pub fn put(&self, obj: T) {
+        self.inner.live.set(self.inner.live.get() + 1);
+        self.put_idle(obj);
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.objects.borrow().len()
+    }
+
+    async fn take_object(
+        &self,
+        phase: &Cell<Phase>,
+    ) -> Result<LocalPoolGuard<T>, Error<T::Error>> {
+        let mut tries = 0;
+        let mut connect_tries = 0;
+        let mut backoff = self.inner.backoff.clone();
+        let mut connect_backoff = self.inner.connect_backoff.clone();
+
+        loop {
+            phase.set(Phase::Waiting);
+            let idle = match self.acquire() {
+                LocalAcquire::Object(object) => Some(object),
+                LocalAcquire::Reserved => None,
+                LocalAcquire::Waiting(receiver) => {
+                    match (LocalWaiter { pool: self, receiver }).await {
+                        Ok(Handoff::Object(object)) => Some(object),
+                        Ok(Handoff::Slot) => None,
+                        Err(Canceled) => continue,
+                    }
+                }
+            };
+
+            let object = match idle {
+                Some(object) => object,
+                None => {
+                    phase.set(Phase::Connecting);
+                    let reservation = Reservation { pool: self };
+                    match (self.inner.factory)().await {
+                        Ok(object) => {
+                            mem::forget(reservation);
+                            object
+                        }
+                        Err(err) => {
+                            debug!("local factory failed, err={}", &err);
+                            drop(reservation);
+                            connect_tries += 1;
+                            if self.inner.connect_max_tries.is_some()
+                                && connect_tries >= self.inner.connect_max_tries.unwrap()
+                            {
+                                return Err(Error::MaxTriesExceeded {
+                                    last_error: Box::new(Error::Factory(err)),
+                                    tries: connect_tries,
+                                });
+                            }
+
+                            phase.set(Phase::Backoff);
+                            self.backoff(connect_backoff.next_delay()).await;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            phase.set(Phase::Testing);
+            let mut guard = LocalPoolGuard::new(object, self.clone());
+            match future::poll_fn(|cx| guard.test_poll(cx)).await {
+                Ok(true) => return Ok(guard),
+                Ok(false) => {
+                    debug!("local object unusable, discarding it");
+                    guard.discard();
+                    self.backoff(None).await;
+                }
+                Err(err) => {
+                    debug!("local object test_poll, err={}", &err);
+                    guard.discard();
+                    tries += 1;
+                    if self.inner.max_tries.is_some() && tries >= self.inner.max_tries.unwrap() {
+                        return Err(Error::MaxTriesExceeded {
+                            last_error: Box::new(Error::Validation(err)),
+                            tries,
+                        });
+                    }
+
+                    phase.set(Phase::Backoff);
+                    self.backoff(backoff.next_delay()).await;
+                }
+            }
+        }
+    }
+
+    /// Waits for the backoff `delay`, or without one yields to the executor once,
+    /// so that a failing factory can't starve its timers and other tasks.
+    async fn backoff(&self, delay: Option<Duration>) {
+        if let Some(delay) = delay {
+            self.inner.runtime.sleep(delay).await;
+            return;
+        }
+
+        let mut yielded = false;
+        future::poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+    }
+
+    /// Takes an idle object, reserves a slot for a new one or queues the caller
+    /// behind the other waiting takes.
+    fn acquire(&self) -> LocalAcquire<T> {
+        if let Some(object) = self.inner.objects.borrow_mut().pop_front() {
+            return LocalAcquire::Object(object);
+        }
+
+        let live = self.inner.live.get();
+        if self.inner.max_size.map(|max| live < max).unwrap_or(true) {
+            self.inner.live.set(live + 1);
+            return LocalAcquire::Reserved;
+        }
+
+        LocalAcquire::Waiting(self.inner.waiters.borrow_mut().push())
+    }
+
+    /// Returns an object to the oldest waiting take, or to the idle queue.
+    fn put_idle(&self, obj: T) {
+        let obj = match self.inner.waiters.borrow_mut().hand_object(obj) {
+            Ok(()) => return,
+            Err(obj) => obj,
+        };
+
+        let evicted = {
+            let mut objects = self.inner.objects.borrow_mut();
+            let capacity = self.inner.capacity.unwrap_or_else(|| 0);
+            let evicted = if capacity > 0 && objects.len() >= capacity {
+                objects.pop_back()
+            } else {
+                None
+            };
+
+            objects.push_back(obj);
+            evicted
+        };
+
+        if let Some(evicted) = evicted {
+            self.discard(evicted);
+        }
+    }
+
+    fn discard(&self, obj: T) {
+        drop(obj);
+        self.release();
+    }
+
+    /// Gives back a slot, or hands it to the oldest waiting take.
+    fn release(&self) {
+        if self.inner.waiters.borrow_mut().hand_slot() {
+            return;
+        }
+        self.inner.live.set(self.inner.live.get() - 1);
+    }
+}
+
+pub struct LocalPoolGuard<T>
+where
+    T: PoolObject + 'static,
+{
+    object: Option<T>,
+    invalid: bool,
+    pool: LocalPool<T>,
+}
+
+impl<T> LocalPoolGuard<T>
+where
+    T: PoolObject + 'static,
+{
+    fn new(object: T, pool: LocalPool<T>) -> LocalPoolGuard<T> {
+        LocalPoolGuard {
+            object: Some(object),
+            invalid: false,
+            pool,
+        }
+    }
+
+    fn discard(&mut self) {
+        if let Some(object) = self.object.take() {
+            self.pool.discard(object);
+        }
+    }
+
+    /// Like `PoolGuard::invalidate`, the object is discarded when the guard is dropped.
+    pub fn invalidate(&mut self) {
+        self.invalid = true;
+    }
+
+    pub fn detach(&mut self) -> Option<T> {
+        let object = self.object.take();
+        if object.is_some() {
+            self.pool.release();
+        }
+        object
+    }
+}
+
+impl<T> std::ops::Deref for LocalPoolGuard<T>
+where
+    T: PoolObject + 'static,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.object.as_ref().expect("deref LocalPoolGuard no inner")
+    }
+}
+
+impl<T> std::ops::DerefMut for LocalPoolGuard<T>
+where
+    T: PoolObject + 'static,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.object.as_mut().expect("deref LocalPoolGuard no inner")
+    }
+}
+
+impl<T> Drop for LocalPoolGuard<T>
+where
+    T: PoolObject + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(mut object) = self.object.take() {
+            if self.invalid {
+                self.pool.discard(object);
+                return;
+            }
+
+            let mut cx = Context::from_waker(noop_waker_ref());
+            match object.recycle_poll(&mut cx) {
+                Poll::Ready(Ok(())) => self.pool.put_idle(object),
+                Poll::Ready(Err(err)) => {
+                    debug!("local object recycle failed, err={}", &err);
+                    self.pool.discard(object);
+                }
+                Poll::Pending => {
+                    debug!("local object recycle pending, discarding it");
+                    self.pool.discard(object);
+                }
+            }
+        }
+    }
+}
+
+pub struct LocalPoolBuilder<T>
+where
+    T: PoolObject + 'static,
+{
+    _factory: Option<Box<LocalObjectFactory<T>>>,
+    _timeout: Option<Duration>,
+    _max_tries: Option<usize>,
+    _connect_max_tries: Option<usize>,
+    _capacity: Option<usize>,
+    _max_size: Option<usize>,
+    _backoff: BackoffStrategy,
+    _connect_backoff: BackoffStrategy,
+    _runtime: Option<Arc<dyn Runtime>>,
+}
+
+impl<T> LocalPoolBuilder<T>
+where
+    T: PoolObject + 'static,
+{
+    pub fn new() -> LocalPoolBuilder<T> {
+        LocalPoolBuilder {
+            _factory: None,
+            _timeout: Some(Duration::from_secs(10)),
+            _max_tries: Some(10),
+            _connect_max_tries: Some(10),
+            _capacity: None,
+            _max_size: None,
+            _backoff: BackoffStrategy::None,
+            _connect_backoff: BackoffStrategy::Exponential(
+                ExponentialBackoff::from_millis(2)
+                    .factor(5)
+                    .max_delay(Duration::from_secs(1)),
+            ),
+            _runtime: None,
+        }
+    }
+
+    pub fn factory<F>(mut self, factory: impl Fn() -> F + 'static) -> Self
+    where
+        F: Future<Output = Result<T, T::Error>> + 'static,
+    {
+        self._factory = Some(Box::new(move || Box::pin(factory())));
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self._timeout = timeout;
+        self
+    }
+
+    pub fn max_tries(mut self, max_tries: Option<usize>) -> Self {
+        self._max_tries = max_tries;
+        self
+    }
+
+    pub fn connect_max_tries(mut self, connect_max_tries: Option<usize>) -> Self {
+        self._connect_max_tries = connect_max_tries;
+        self
+    }
+
+    pub fn capacity(mut self, capacity: Option<usize>) -> Self {
+        self._capacity = capacity;
+        self
+    }
+
+    pub fn max_size(mut self, max_size: Option<usize>) -> Self {
+        self._max_size = max_size;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: BackoffStrategy) -> Self {
+        self._backoff = backoff;
+        self
+    }
+
+    pub fn connect_backoff(mut self, connect_backoff: BackoffStrategy) -> Self {
+        self._connect_backoff = connect_backoff;
+        self
+    }
+
+    /// The timer of the pool, `TokioRuntime` by default. Nothing is spawned on it.
+    pub fn runtime(mut self, runtime: impl Runtime) -> Self {
+        self._runtime = Some(Arc::new(runtime));
+        self
+    }
+
+    pub fn build(self) -> LocalPool<T> {
+        LocalPool {
+            inner: Rc::new(LocalPoolInner {
+                factory: self._factory.expect("A pool connector is required"),
+                objects: RefCell::new(VecDeque::new()),
+                timeout: self._timeout,
+                max_tries: self._max_tries,
+                connect_max_tries: self._connect_max_tries,
+                capacity: self._capacity,
+                max_size: self._max_size,
+                backoff: self._backoff,
+                connect_backoff: self._connect_backoff,
+                runtime: self
+                    ._runtime
+                    .or_else(default_runtime)
+                    .expect("A pool runtime is required"),
+                live: Cell::new(0),
+                waiters: RefCell::new(Waiters::new()),
+            }),
+        }
+    }
+}
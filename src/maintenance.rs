@@ -0,0 +1,125 @@
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::Context;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{Future, FutureExt, Poll};
+
+use crate::factory::FactoryFuture;
+use crate::object::PoolObject;
+use crate::pool::{Pool, PoolInner};
+use crate::runtime::{Runtime, RuntimeFuture};
+
+/// Background upkeep of a `Pool`, returned by `Pool::maintenance`.
+///
+/// Right away and then every `maintenance_interval` it evicts the objects which were
+/// idle for longer than `idle_timeout` or outlived `max_lifetime`, and tops the pool
+/// up to `min_idle` objects through the factory. It only holds a weak reference to
+/// the pool and completes as soon as the last `Pool` clone is dropped or the pool is closed.
+pub struct Maintenance<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pool: Weak<PoolInner<T>>,
+    interval: Duration,
+    runtime: Arc<dyn Runtime>,
+    delay: Option<RuntimeFuture>,
+    creating: FuturesUnordered<FactoryFuture<T>>,
+}
+
+impl<T> Maintenance<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pub(crate) fn new(pool: &Pool<T>) -> Maintenance<T> {
+        Maintenance {
+            pool: pool.downgrade(),
+            interval: pool.inner.maintenance_interval,
+            runtime: pool.inner.runtime.clone(),
+            delay: None,
+            creating: FuturesUnordered::new(),
+        }
+    }
+
+    fn top_up(&mut self, pool: &Pool<T>) {
+        let min_idle = match pool.inner.min_idle {
+            Some(min_idle) => min_idle,
+            None => return,
+        };
+
+        let missing = min_idle.saturating_sub(pool.size() + self.creating.len());
+
+        for _ in 0..missing {
+            if !pool.try_reserve() {
+                debug!("max_size reached, can't top up the pool");
+                break;
+            }
+
+            match pool.create() {
+                Some(factory_future) => self.creating.push(factory_future),
+                None => {
+                    debug!("circuit breaker open, can't top up the pool");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Future for Maintenance<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            while let Poll::Ready(Some(res)) = self.creating.poll_next_unpin(cx) {
+                let pool = match Pool::upgrade(&self.pool) {
+                    Some(pool) => pool,
+                    None => continue,
+                };
+
+                match res {
+                    Ok(object) => pool.put_idle(pool.new_entry(object)),
+                    Err(err) => {
+                        debug!("pool top up failed, err={}", &err);
+                        pool.release();
+                    }
+                }
+            }
+
+            let pool = match Pool::upgrade(&self.pool) {
+                Some(pool) => pool,
+                None => {
+                    debug!("pool dropped, stopping maintenance");
+                    return Poll::Ready(());
+                }
+            };
+
+            if pool.is_closed() {
+                debug!("pool closed, stopping maintenance");
+                //the objects being created will never make it to the pool
+                (0..self.creating.len()).for_each(|_| pool.release());
+                self.creating = FuturesUnordered::new();
+                return Poll::Ready(());
+            }
+
+            if let Some(ref mut delay) = self.delay {
+                if delay.poll_unpin(cx).is_pending() {
+                    //registered while we hold the pool, so that its close or drop wakes us
+                    let mut wakers = pool.inner.maintenance.lock();
+                    if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                        wakers.push(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+            }
+
+            pool.evict_expired();
+            self.top_up(&pool);
+            self.delay = Some(self.runtime.sleep(self.interval));
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::health::HealthCheckStats;
+
+/// What the objects of a pool are doing right now, returned by `Pool::state`.
+/// The counts are read one after the other, so they may be slightly off under load.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolState {
+    /// Objects waiting in the pool.
+    pub idle: usize,
+    /// Objects checked out through a `PoolGuard`.
+    pub in_use: usize,
+    /// Objects the factory is still creating.
+    pub pending: usize,
+    /// Takes waiting for an object to be returned because `max_size` is reached.
+    pub waiters: usize,
+}
+
+/// Counters accumulated over the life of a pool, returned by `Pool::metrics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Objects created by the factory or added with `Pool::put`.
+    pub created: usize,
+    /// Objects the pool discarded, e.g. evicted, expired or failing their health check.
+    pub destroyed: usize,
+    /// Health checks which returned `Ok(false)` or an error.
+    pub failed_health_checks: usize,
+    /// Takes which ran out of `timeout`.
+    pub acquire_timeouts: usize,
+    /// How long successful takes waited for their object.
+    pub wait_time: WaitHistogram,
+}
+
+/// The upper bounds of the `WaitHistogram` buckets, the last bucket has none.
+const WAIT_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1_000, 5_000];
+
+/// A histogram of how long takes waited for their object.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WaitHistogram {
+    counts: Vec<usize>,
+    total: Duration,
+}
+
+impl WaitHistogram {
+    /// The buckets as the upper bound of each bucket, exclusive, and how many takes fell
+    /// in it. The last bucket has no upper bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, usize)> + '_ {
+        let bounds = WAIT_BUCKETS_MS
+            .iter()
+            .map(|ms| Some(Duration::from_millis(*ms)))
+            .chain(Some(None));
+        bounds.zip(self.counts.iter().cloned())
+    }
+
+    /// How many takes were recorded.
+    pub fn count(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// The average wait, zero if nothing was recorded.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::from_secs(0),
+            count => self.total / count as u32,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct MetricsCounters {
+    created: AtomicUsize,
+    destroyed: AtomicUsize,
+    acquire_timeouts: AtomicUsize,
+    //the factory calls in flight
+    pub(crate) creating: AtomicUsize,
+    //one more than there are bounds
+    waits: [AtomicUsize; 9],
+    wait_total_us: AtomicU64,
+}
+
+impl MetricsCounters {
+    pub(crate) fn created(&self) {
+        self.created.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn destroyed(&self) {
+        self.destroyed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn acquire_timeout(&self) {
+        self.acquire_timeouts.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn waited(&self, wait: Duration) {
+        let ms = wait.as_millis();
+        let bucket = WAIT_BUCKETS_MS
+            .iter()
+            .position(|bound| ms < u128::from(*bound))
+            .unwrap_or(WAIT_BUCKETS_MS.len());
+        self.waits[bucket].fetch_add(1, Ordering::SeqCst);
+        self.wait_total_us
+            .fetch_add(wait.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    pub(crate) fn snapshot(&self, health: HealthCheckStats) -> PoolMetrics {
+        PoolMetrics {
+            created: self.created.load(Ordering::SeqCst),
+            destroyed: self.destroyed.load(Ordering::SeqCst),
+            failed_health_checks: health.unusable + health.failed,
+            acquire_timeouts: self.acquire_timeouts.load(Ordering::SeqCst),
+            wait_time: WaitHistogram {
+                counts: self
+                    .waits
+                    .iter()
+                    .map(|count| count.load(Ordering::SeqCst))
+                    .collect(),
+                total: Duration::from_micros(self.wait_total_us.load(Ordering::SeqCst)),
+            },
+        }
+    }
+}
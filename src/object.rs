@@ -1,7 +1,22 @@
+use std::error::Error;
+
 use futures::task::Context;
 use futures::Poll;
-use tokio::io::Result;
 
 pub trait PoolObject {
-    fn test_poll(&mut self, cx: &mut Context) -> Poll<Result<bool>>;
+    /// The error of the factory, `test_poll` and `recycle_poll`, handed back
+    /// by `Pool::take` wrapped in `fut_pool::Error`.
+    type Error: Error + Send + 'static;
+
+    fn test_poll(&mut self, cx: &mut Context) -> Poll<Result<bool, Self::Error>>;
+
+    /// Resets the object before it goes back to the idle queue, e.g. rolls back
+    /// a transaction or drains unread bytes. On error the object is discarded.
+    ///
+    /// It's polled once when the guard is dropped. If that returns `Pending`, a `Pool`
+    /// finishes it on a task spawned on its runtime, while a `LocalPool` can't spawn and
+    /// discards the object instead.
+    fn recycle_poll(&mut self, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
 }
@@ -1,91 +1,612 @@
-use std::collections::VecDeque;
-use std::io::Result;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
-use parking_lot::RwLock;
+use futures::channel::oneshot::Receiver;
+use futures::stream::{self, StreamExt};
+use futures::task::Waker;
+use parking_lot::Mutex;
 
-use crate::backoff::BackoffStrategy;
+use crate::backoff::{jitter, BackoffStrategy};
+use crate::breaker::{Breaker, CircuitState};
 use crate::builder::PoolBuilder;
-use crate::factory::ObjectFactory;
+use crate::drain::Drain;
+use crate::error::Error;
+use crate::factory::{FactoryFuture, ObjectDestroyer, ObjectFactory};
 use crate::guard::PoolGuard;
+use crate::health::{HealthCheck, HealthCheckCounters, HealthCheckStats};
+use crate::keyed::SharedLimit;
+use crate::events::PoolEventListener;
+use crate::maintenance::Maintenance;
+use crate::metrics::{MetricsCounters, PoolMetrics, PoolState};
 use crate::object::PoolObject;
+use crate::runtime::Runtime;
 use crate::taker::PoolTaker;
+use crate::waiters::{Handoff, Waiters};
 
 pub struct Pool<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
-    pub(crate) factory: Arc<ObjectFactory<T>>,
-    pub(crate) objects: Arc<RwLock<VecDeque<T>>>,
+    pub(crate) inner: Arc<PoolInner<T>>,
+}
+
+pub(crate) struct PoolInner<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pub(crate) factory: Box<ObjectFactory<T>>,
+    pub(crate) destroyer: Option<Box<ObjectDestroyer<T>>>,
+    //a Mutex rather than a RwLock, so that objects only have to be Send
+    pub(crate) objects: Mutex<VecDeque<Idle<T>>>,
 
     pub(crate) timeout: Option<Duration>,
     pub(crate) max_tries: Option<usize>,
+    pub(crate) connect_max_tries: Option<usize>,
     pub(crate) capacity: Option<usize>,
+    pub(crate) max_size: Option<usize>,
+    pub(crate) min_idle: Option<usize>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_lifetime: Option<Duration>,
+    pub(crate) max_lifetime_jitter: Duration,
+    pub(crate) maintenance_interval: Duration,
+    pub(crate) initialize_concurrency: Option<usize>,
     pub(crate) backoff: BackoffStrategy,
+    pub(crate) connect_backoff: BackoffStrategy,
+    pub(crate) health_check: HealthCheck,
+    pub(crate) health_stats: HealthCheckCounters,
+    pub(crate) metrics: Arc<MetricsCounters>,
+    pub(crate) events: Arc<dyn PoolEventListener>,
+    pub(crate) runtime: Arc<dyn Runtime>,
+    //the limit shared with the other partitions of a `KeyedPool`
+    pub(crate) shared: Option<Arc<SharedLimit<T>>>,
+    pub(crate) breaker: Option<Arc<Breaker>>,
+
+    //idle + checked out + being created by the factory
+    pub(crate) live: AtomicUsize,
+    //takers waiting for an object or a free slot, oldest first.
+    //always locked before `objects`, `closed` is set under it
+    pub(crate) waiters: Mutex<Waiters<Entry<T>>>,
+    pub(crate) closed: AtomicBool,
+    //`Drain` futures waiting for `live` to reach 0
+    pub(crate) drains: Mutex<Vec<Waker>>,
+    //`Maintenance` futures waiting for their next run, woken when the pool is closed or dropped
+    pub(crate) maintenance: Mutex<Vec<Waker>>,
+    //the takers in flight by id, woken when the pool is closed whatever they wait for
+    pub(crate) takers: Mutex<HashMap<usize, Waker>>,
+    pub(crate) next_taker: AtomicUsize,
+}
+
+impl<T> PoolInner<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    pub(crate) fn wake_maintenance(&self) {
+        let wakers: Vec<Waker> = self.maintenance.lock().drain(..).collect();
+        wakers.into_iter().for_each(|waker| waker.wake());
+    }
+}
+
+impl<T> Drop for PoolInner<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    fn drop(&mut self) {
+        //the maintenance only holds a weak reference, let it see the pool is gone
+        self.wake_maintenance();
+
+        //every object is back once the last `Pool` clone is gone, the idle ones are torn down
+        for idle in self.objects.get_mut().drain(..) {
+            if let Some(ref destroyer) = self.destroyer {
+                self.runtime.spawn(destroyer(idle.entry.object));
+            }
+            self.metrics.destroyed();
+            if let Some(ref shared) = self.shared {
+                shared.release();
+            }
+        }
+    }
+}
+
+/// An object owned by the pool.
+pub(crate) struct Entry<T> {
+    pub(crate) object: T,
+    //when `max_lifetime` runs out, jitter included
+    pub(crate) expires_at: Option<Instant>,
+}
+
+impl<T> Entry<T> {
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at
+            .map(|expires_at| now >= expires_at)
+            .unwrap_or(false)
+    }
+}
+
+/// An object in the idle queue.
+pub(crate) struct Idle<T> {
+    pub(crate) entry: Entry<T>,
+    pub(crate) since: Instant,
+}
+
+impl<T> Idle<T> {
+    pub(crate) fn new(entry: Entry<T>, now: Instant) -> Idle<T> {
+        Idle { entry, since: now }
+    }
+}
+
+/// The outcome of `Pool::acquire`.
+pub(crate) enum Acquire<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    //along with how long it was idle
+    Object(PoolGuard<T>, Duration),
+    Reserved,
+    Waiting(Receiver<Handoff<Entry<T>>>),
+    //the limit shared with other partitions is reached
+    Full(Arc<SharedLimit<T>>),
+    Closed,
+}
+
+/// The outcome of `Pool::initialize`.
+#[derive(Debug)]
+pub struct InitializeReport<E> {
+    /// How many new objects the factory created for the pool,
+    /// idle objects which were already pooled don't count.
+    pub created: usize,
+    /// The errors of the objects which couldn't be created.
+    pub errors: Vec<Error<E>>,
+}
+
+impl<E> InitializeReport<E> {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
 }
 
 impl<T> Clone for Pool<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
     fn clone(&self) -> Self {
         Pool {
-            factory: self.factory.clone(),
-            backoff: self.backoff.clone(),
-            objects: self.objects.clone(),
-            timeout: self.timeout.clone(),
-            max_tries: self.max_tries.clone(),
-            capacity: self.capacity.clone(),
+            inner: self.inner.clone(),
         }
     }
 }
 
 impl<T> Pool<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
     pub fn builder() -> PoolBuilder<T> {
         PoolBuilder::new()
     }
 
-    pub async fn take(&self) -> Result<PoolGuard<T>> {
+    pub async fn take(&self) -> Result<PoolGuard<T>, Error<T::Error>> {
         PoolTaker::<T>::new(self.clone()).await
     }
 
     pub fn try_take(&self) -> Option<PoolGuard<T>> {
-        let mut connections = self.objects.write();
-        let conn = connections.pop_front()?;
-        Some(PoolGuard::new(conn, self.clone()))
+        let (idle, expired) = self.pop_idle();
+        expired.into_iter().for_each(|obj| self.discard(obj));
+        Some(PoolGuard::new(idle?.entry, self.clone()))
     }
 
     pub fn put(&self, obj: T) {
-        let mut objects = self.objects.write();
-        let capacity = self.capacity.unwrap_or_else(|| 0);
-        if capacity > 0 && objects.len() >= capacity {
-            objects.pop_back();
+        self.inner.live.fetch_add(1, Ordering::SeqCst);
+        if let Some(ref shared) = self.inner.shared {
+            shared.add();
+        }
+        self.inner.metrics.created();
+        self.put_idle(self.new_entry(obj));
+    }
+
+    /// Calls the factory for a slot which is already reserved. If the circuit breaker
+    /// doesn't let the call through the slot is released and `None` is returned.
+    pub(crate) fn create(&self) -> Option<FactoryFuture<T>> {
+        let breaker = match self.inner.breaker {
+            Some(ref breaker) => match breaker.try_call(self.now()) {
+                Some(call) => Some((breaker.clone(), call)),
+                None => {
+                    self.release();
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        Some(FactoryFuture::new(self, breaker))
+    }
+
+    /// Wraps a freshly created object, its lifetime starts now.
+    pub(crate) fn new_entry(&self, object: T) -> Entry<T> {
+        let expires_at = self.inner.max_lifetime.map(|max_lifetime| {
+            //spread the expiry so objects created together aren't all rotated at once
+            let lifetime = max_lifetime
+                .checked_sub(jitter(self.inner.max_lifetime_jitter))
+                .unwrap_or_else(|| Duration::from_secs(0));
+            self.now() + lifetime
+        });
+
+        Entry { object, expires_at }
+    }
+
+    /// Returns an object which is already accounted for in `live` to the pool.
+    /// The oldest waiting taker gets it, otherwise it goes to the idle queue.
+    /// Once the pool is closed the object is discarded instead.
+    pub(crate) fn put_idle(&self, entry: Entry<T>) {
+        let mut waiters = self.inner.waiters.lock();
+        if self.is_closed() {
+            drop(waiters);
+            self.discard(entry.object);
+            return;
+        }
+
+        let entry = match waiters.hand_object(entry) {
+            Ok(()) => return,
+            Err(entry) => entry,
+        };
+        //a take of this partition may wait for a shared slot rather than in our queue
+        let entry = match self.inner.shared {
+            Some(ref shared) => match shared.hand_object(self, entry) {
+                Ok(()) => return,
+                Err(entry) => entry,
+            },
+            None => entry,
+        };
+
+        let evicted = {
+            let mut objects = self.inner.objects.lock();
+            let capacity = self.inner.capacity.unwrap_or_else(|| 0);
+            let evicted = if capacity > 0 && objects.len() >= capacity {
+                objects.pop_back()
+            } else {
+                None
+            };
+
+            objects.push_back(Idle::new(entry, self.now()));
+            evicted
+        };
+
+        drop(waiters);
+        if let Some(evicted) = evicted {
+            self.inner.events.on_evict();
+            self.discard(evicted.entry.object);
+        }
+        if let Some(ref shared) = self.inner.shared {
+            //only takes of other partitions are left waiting, this object has to make room
+            if shared.has_waiters() {
+                self.evict_oldest();
+            }
+        }
+    }
+
+    /// Discards the oldest idle object to free its slot, returns false if there is none.
+    pub(crate) fn evict_oldest(&self) -> bool {
+        let oldest = self.inner.objects.lock().pop_front();
+        match oldest {
+            Some(idle) => {
+                self.inner.events.on_evict();
+                self.discard(idle.entry.object);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pops the oldest idle object, along with the ones which were idle for too long
+    /// or outlived `max_lifetime`. The expired objects must be discarded once no locks are held.
+    fn pop_idle(&self) -> (Option<Idle<T>>, Vec<T>) {
+        let mut objects = self.inner.objects.lock();
+        let mut expired = Vec::new();
+        while let Some(idle) = objects.pop_front() {
+            if self.is_idle_expired(&idle) {
+                expired.push(idle.entry.object);
+            } else {
+                return (Some(idle), expired);
+            }
+        }
+
+        (None, expired)
+    }
+
+    fn is_idle_expired(&self, idle: &Idle<T>) -> bool {
+        let now = self.now();
+        let idle_too_long = match self.inner.idle_timeout {
+            Some(idle_timeout) => now.duration_since(idle.since) >= idle_timeout,
+            None => false,
+        };
+
+        idle_too_long || idle.entry.is_expired(now)
+    }
+
+    /// Takes an idle object, reserves a slot for a new one or queues the caller
+    /// behind the other waiting takers.
+    pub(crate) fn acquire(&self) -> Acquire<T> {
+        let mut waiters = self.inner.waiters.lock();
+        if self.is_closed() {
+            return Acquire::Closed;
         }
 
-        objects.push_back(obj);
+        let (idle, expired) = self.pop_idle();
+        let acquired = if let Some(idle) = idle {
+            let idle_for = self.now().duration_since(idle.since);
+            Acquire::Object(PoolGuard::new(idle.entry, self.clone()), idle_for)
+        } else if self.try_reserve() {
+            Acquire::Reserved
+        } else {
+            match self.inner.shared {
+                //no object of this pool will free a slot, other partitions have to
+                Some(ref shared) if !self.is_full() => Acquire::Full(shared.clone()),
+                _ => Acquire::Waiting(waiters.push()),
+            }
+        };
+
+        drop(waiters);
+        expired.into_iter().for_each(|obj| self.discard(obj));
+        acquired
+    }
+
+    /// Reserves a slot for a new object. Returns false if `max_size` objects are already live,
+    /// or if the limit shared with the other partitions of a `KeyedPool` is reached.
+    pub(crate) fn try_reserve(&self) -> bool {
+        if !self.try_reserve_own() {
+            return false;
+        }
+
+        match self.inner.shared {
+            Some(ref shared) if !shared.try_reserve() => {
+                self.inner.live.fetch_sub(1, Ordering::SeqCst);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Reserves a slot of this pool only, `max_size` permitting.
+    pub(crate) fn try_reserve_own(&self) -> bool {
+        let max_size = match self.inner.max_size {
+            Some(max_size) => max_size,
+            None => {
+                self.inner.live.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        };
+
+        let mut live = self.inner.live.load(Ordering::SeqCst);
+        loop {
+            if live >= max_size {
+                return false;
+            }
+
+            match self.inner.live.compare_exchange(
+                live,
+                live + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => live = actual,
+            }
+        }
+    }
+
+    /// Gives back a slot taken by `try_reserve` or `put`, the object is gone.
+    /// The slot is handed to the oldest waiting taker if there is one.
+    pub(crate) fn release(&self) {
+        let mut waiters = self.inner.waiters.lock();
+        if waiters.hand_slot() {
+            return;
+        }
+
+        let live = self.inner.live.fetch_sub(1, Ordering::SeqCst) - 1;
+        drop(waiters);
+        if let Some(ref shared) = self.inner.shared {
+            shared.release();
+        }
+        if live == 0 && self.is_closed() {
+            self.inner.drains.lock().drain(..).for_each(|waker| waker.wake());
+        }
+    }
+
+    /// Counts a `test_poll` result and reports it to the event listener.
+    pub(crate) fn health_checked(&self, res: &Result<bool, T::Error>) {
+        self.inner.health_stats.record(res);
+        let res = match res {
+            Ok(usable) => Ok(*usable),
+            Err(err) => Err(err as &(dyn StdError + 'static)),
+        };
+        self.inner.events.on_health_check(res);
+    }
+
+    /// Disposes of an object the pool owns and frees its slot.
+    /// The destroyer, if any, runs on a spawned task.
+    pub(crate) fn discard(&self, obj: T) {
+        match self.inner.destroyer {
+            Some(ref destroyer) => self.inner.runtime.spawn(destroyer(obj)),
+            None => drop(obj),
+        }
+        self.inner.metrics.destroyed();
+        self.release();
+    }
+
+    /// Discards the idle objects which were idle for longer than `idle_timeout`
+    /// or outlived `max_lifetime`.
+    pub(crate) fn evict_expired(&self) {
+        if self.inner.idle_timeout.is_none() && self.inner.max_lifetime.is_none() {
+            return;
+        }
+
+        let mut expired = Vec::new();
+        {
+            let mut objects = self.inner.objects.lock();
+            let mut i = 0;
+            while i < objects.len() {
+                if self.is_idle_expired(&objects[i]) {
+                    expired.push(objects.remove(i).unwrap().entry.object);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        if !expired.is_empty() {
+            debug!("evicting {} idle objects", expired.len());
+        }
+        expired.into_iter().for_each(|obj| self.discard(obj));
+    }
+
+    /// A future which evicts expired idle objects and keeps `min_idle` objects in the pool.
+    /// It has to be spawned and it completes once the last `Pool` clone is dropped.
+    pub fn maintenance(&self) -> Maintenance<T> {
+        Maintenance::new(self)
+    }
+
+    /// Closes the pool. Idle objects are discarded right away, `take` fails with
+    /// `Error::Closed` and objects returned later are discarded instead of pooled.
+    pub fn close(&self) {
+        let idle: Vec<Idle<T>> = {
+            let mut waiters = self.inner.waiters.lock();
+            if self.inner.closed.swap(true, Ordering::SeqCst) {
+                return;
+            }
+
+            //dropping the senders wakes the waiting takers, they see the pool is closed
+            waiters.clear();
+            let mut objects = self.inner.objects.lock();
+            objects.drain(..).collect()
+        };
+
+        //takers which are creating, testing or backing off see the pool is closed
+        let takers: Vec<Waker> = self.inner.takers.lock().drain().map(|(_, waker)| waker).collect();
+        takers.into_iter().for_each(|waker| waker.wake());
+        self.inner.wake_maintenance();
+        debug!("pool closed, discarding {} idle objects", idle.len());
+        idle.into_iter()
+            .for_each(|idle| self.discard(idle.entry.object));
+        if let Some(ref shared) = self.inner.shared {
+            //takes waiting for a shared slot see the pool is closed
+            shared.cancel_waiters();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    /// Closes the pool and resolves once every checked out object has come back.
+    pub fn drain(&self) -> Drain<T> {
+        self.close();
+        Drain::new(self.clone())
+    }
+
+    pub fn health_check_stats(&self) -> HealthCheckStats {
+        self.inner.health_stats.snapshot()
+    }
+
+    /// How many objects are idle, checked out and being created, and how many takes wait.
+    pub fn state(&self) -> PoolState {
+        let waiters = self.inner.waiters.lock().len();
+        let idle = self.size();
+        let pending = self.inner.metrics.creating.load(Ordering::SeqCst);
+        PoolState {
+            idle,
+            in_use: self.live().saturating_sub(idle + pending),
+            pending,
+            waiters,
+        }
+    }
+
+    /// The state of the circuit breaker, `None` if the pool has none.
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.inner
+            .breaker
+            .as_ref()
+            .map(|breaker| breaker.state(self.now()))
+    }
+
+    /// The counters accumulated since the pool was built.
+    pub fn metrics(&self) -> PoolMetrics {
+        self.inner
+            .metrics
+            .snapshot(self.inner.health_stats.snapshot())
+    }
+
+    /// The current time of the pool's runtime.
+    pub(crate) fn now(&self) -> Instant {
+        self.inner.runtime.now()
+    }
+
+    pub(crate) fn live(&self) -> usize {
+        self.inner.live.load(Ordering::SeqCst)
+    }
+
+    fn is_full(&self) -> bool {
+        self.inner
+            .max_size
+            .map(|max_size| self.live() >= max_size)
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn downgrade(&self) -> Weak<PoolInner<T>> {
+        Arc::downgrade(&self.inner)
+    }
+
+    pub(crate) fn upgrade(inner: &Weak<PoolInner<T>>) -> Option<Pool<T>> {
+        inner.upgrade().map(|inner| Pool { inner })
     }
 
     pub fn size(&self) -> usize {
-        self.objects.read().len()
+        self.inner.objects.lock().len()
     }
 
-    pub async fn initialize(&self, amount: usize) -> Result<()> {
+    /// Fills the pool with `amount` objects, running up to `initialize_concurrency`
+    /// takes at once. Failed takes don't stop the others, they are collected in the report.
+    pub async fn initialize(&self, amount: usize) -> InitializeReport<T::Error> {
         let amount = self
+            .inner
             .capacity
             .map(|cap| if cap > 0 && amount > cap { cap } else { amount })
             .unwrap_or(amount);
+        let amount = self
+            .inner
+            .max_size
+            .map(|max| if amount > max { max } else { amount })
+            .unwrap_or(amount);
+        let concurrency = self.inner.initialize_concurrency.unwrap_or(amount).max(1);
 
-        let mut initialized = Vec::with_capacity(amount);
-        for _ in 0..amount {
-            initialized.push(self.take().await?.detach().unwrap());
+        let created_before = self.metrics().created;
+        //the objects are held until all takes are done, so that none is taken twice
+        let results: Vec<Result<PoolGuard<T>, Error<T::Error>>> = stream::iter(0..amount)
+            .map(|_| PoolTaker::new(self.clone()))
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+
+        let mut report = InitializeReport {
+            created: 0,
+            errors: Vec::new(),
+        };
+        let mut taken = 0;
+        for res in results {
+            match res {
+                Ok(_) => taken += 1,
+                Err(err) => {
+                    debug!("initialize take failed, err={}", &err);
+                    report.errors.push(err);
+                }
+            }
         }
-        initialized.into_iter().for_each(|c| self.put(c));
 
-        Ok(())
+        //the metrics also count objects created by other takes meanwhile
+        //or discarded by a failed health check, neither made it into the report
+        let created = self.metrics().created - created_before;
+        report.created = created.min(taken);
+        report
     }
 
     pub fn destroy(&self, mut amount: usize) {
@@ -96,7 +617,7 @@ where
 
             if let Some(mut item) = self.try_take() {
                 amount -= 1;
-                item.detach();
+                item.discard();
             } else {
                 break;
             }
@@ -0,0 +1,142 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+
+/// A boxed future run by a `Runtime`.
+pub type RuntimeFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// The clock, timer and executor the pool runs on.
+///
+/// Every deadline, backoff and maintenance delay goes through `sleep`, and background
+/// work - object teardown and recycling which doesn't complete right away - through `spawn`.
+pub trait Runtime: Send + Sync + 'static {
+    /// The current time, idle timeouts and lifetimes are measured against it.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// A future which completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> RuntimeFuture;
+
+    /// Runs a background future to completion.
+    fn spawn(&self, future: RuntimeFuture);
+}
+
+/// Runs the pool on the tokio 0.1 timer and default executor.
+///
+/// Outside of a tokio runtime, e.g. under `futures::executor::block_on`, sleeps fall back
+/// to `futures-timer` and spawned futures run on a thread pool shared by every pool and
+/// started on first use, so the pool keeps working on any executor.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio")]
+impl Runtime for TokioRuntime {
+    fn sleep(&self, duration: Duration) -> RuntimeFuture {
+        use futures::compat::Future01CompatExt;
+
+        let deadline = Instant::now() + duration;
+        let delay = tokio::timer::Delay::new(deadline).compat();
+        Box::pin(async move {
+            //the tokio timer fails when there is none, e.g. outside of a tokio runtime
+            if let Err(err) = delay.await {
+                debug!("tokio timer unavailable, err={}, using futures-timer", err);
+                futures_timer::Delay::new_at(deadline)
+                    .await
+                    .expect("the futures-timer thread is gone");
+            }
+        })
+    }
+
+    fn spawn(&self, future: RuntimeFuture) {
+        use futures::{FutureExt, TryFutureExt};
+        use parking_lot::Mutex;
+        use tokio::executor::{DefaultExecutor, Executor};
+
+        //a failed spawn drops the task, so the future is handed over through a slot
+        //it can be taken back from
+        let slot = Arc::new(Mutex::new(Some(future)));
+        let task = {
+            let slot = slot.clone();
+            async move {
+                let future = slot.lock().take();
+                if let Some(future) = future {
+                    future.await;
+                }
+            }
+        };
+
+        let task = Box::new(Box::pin(task).unit_error().compat());
+        if let Err(err) = DefaultExecutor::current().spawn(task) {
+            debug!("no tokio executor, err={:?}, using the fallback thread pool", err);
+            if let Some(future) = slot.lock().take() {
+                fallback_executor().spawn_ok(future);
+            }
+        }
+    }
+}
+
+/// The thread pool which runs the futures spawned outside of a tokio runtime.
+#[cfg(feature = "tokio")]
+fn fallback_executor() -> &'static futures::executor::ThreadPool {
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+
+    use futures::executor::ThreadPool;
+
+    static EXECUTOR: AtomicPtr<ThreadPool> = AtomicPtr::new(ptr::null_mut());
+
+    let mut executor = EXECUTOR.load(Ordering::SeqCst);
+    if executor.is_null() {
+        let started = ThreadPool::builder()
+            .name_prefix("fut-pool-")
+            .create()
+            .expect("failed to start the fallback thread pool");
+        let started = Box::into_raw(Box::new(started));
+        executor = match EXECUTOR.compare_exchange(
+            ptr::null_mut(),
+            started,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => started,
+            Err(current) => {
+                //another thread started one first, ours shuts down
+                drop(unsafe { Box::from_raw(started) });
+                current
+            }
+        };
+    }
+
+    //the executor is never freed once it's published
+    unsafe { &*executor }
+}
+
+/// Runs the pool on the async-std timer and executor.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStdRuntime {
+    fn sleep(&self, duration: Duration) -> RuntimeFuture {
+        Box::pin(async_std::task::sleep(duration))
+    }
+
+    fn spawn(&self, future: RuntimeFuture) {
+        async_std::task::spawn(future);
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    Some(Arc::new(TokioRuntime))
+}
+
+#[cfg(not(feature = "tokio"))]
+pub(crate) fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    None
+}
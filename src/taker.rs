@@ -1,145 +1,384 @@
-use std::io::{Error, ErrorKind, Result};
+use std::mem;
 use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::task::Context;
 use std::time::{Duration, Instant};
 
-use futures::{ready, try_ready, Future, FutureExt, Poll};
-use futures_timer::Delay;
+use futures::channel::oneshot::Receiver;
+use futures::{ready, Future, FutureExt, Poll};
 
 use crate::backoff::BackoffStrategy;
+use crate::error::{Error, Phase};
+use crate::events::TakeProgress;
+use crate::factory::FactoryFuture;
 use crate::guard::PoolGuard;
 use crate::object::PoolObject;
-use crate::pool::Pool;
+use crate::keyed::SharedLimit;
+use crate::pool::{Acquire, Entry, Pool};
+use crate::runtime::RuntimeFuture;
+use crate::waiters::Handoff;
+
+enum State<T>
+where
+    T: PoolObject + Send + 'static,
+{
+    //look for an idle object, a free slot or get in line
+    Acquire,
+    //max_size is reached, wait for a returned object or a freed slot
+    Waiting(Receiver<Handoff<Entry<T>>>),
+    //the limit shared by the partitions of a KeyedPool is reached, wait in line until
+    //a slot is freed, an idle object of another partition is evicted or one of ours returned
+    Full(Arc<SharedLimit<T>>, Receiver<Handoff<Entry<T>>>),
+    //the factory is creating an object in a slot we reserved
+    Creating(FactoryFuture<T>),
+    //decide whether the object can be handed out, along with how long it was idle
+    Checkout(PoolGuard<T>, Option<Duration>),
+    //the object is being tested with test_poll before it's handed out
+    Testing(PoolGuard<T>),
+    //the factory or test_poll failed, wait before trying again
+    Backoff(RuntimeFuture),
+}
 
 pub struct PoolTaker<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
     pool: Pool<T>,
+    //the key of our waker in the pool's takers
+    id: usize,
     started_at: Instant,
     tries: usize,
-    factory_future_in_progress: Option<Pin<Box<dyn Future<Output = Result<T>>>>>,
+    connect_tries: usize,
+    state: State<T>,
     backoff: BackoffStrategy,
-    first_poll: bool,
-    backoff_delay: Option<Delay>,
+    connect_backoff: BackoffStrategy,
+    deadline: Option<RuntimeFuture>,
 }
 
 impl<T> PoolTaker<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
     pub(crate) fn new(pool: Pool<T>) -> PoolTaker<T> {
+        let runtime = &pool.inner.runtime;
         PoolTaker {
-            started_at: Instant::now(),
+            id: pool.inner.next_taker.fetch_add(1, Ordering::SeqCst),
+            started_at: runtime.now(),
             tries: 0,
-            factory_future_in_progress: None,
-            first_poll: true,
-            backoff: pool.backoff.clone(),
-            backoff_delay: None,
+            connect_tries: 0,
+            state: State::Acquire,
+            backoff: pool.inner.backoff.clone(),
+            connect_backoff: pool.inner.connect_backoff.clone(),
+            deadline: pool.inner.timeout.map(|timeout| runtime.sleep(timeout)),
             pool,
         }
     }
 }
 
-unsafe impl<T> Send for PoolTaker<T> where T: PoolObject {}
-
-unsafe impl<T> Sync for PoolTaker<T> where T: PoolObject {}
+//the taker never pins the object it holds
+impl<T> Unpin for PoolTaker<T> where T: PoolObject + Send + 'static {}
 
 impl<T> PoolTaker<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
+{
+    /// Retries after the backoff `timeout`, or without one once the executor had a chance
+    /// to run its timers and other tasks, so that a failing factory can't starve them.
+    /// With `BackoffStrategy::None` and no `connect_max_tries` a take would otherwise
+    /// call the factory in a loop without ever returning `Pending`.
+    fn retry(&self, timeout: Option<Duration>, cx: &mut Context) -> Poll<State<T>> {
+        debug!("object timeout {:?}", &timeout);
+        match timeout {
+            Some(timeout) => {
+                self.pool.inner.events.on_backoff(timeout, self.progress());
+                Poll::Ready(State::Backoff(self.pool.inner.runtime.sleep(timeout)))
+            }
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.pool.now().duration_since(self.started_at)
+    }
+
+    fn progress(&self) -> TakeProgress {
+        TakeProgress {
+            elapsed: self.elapsed(),
+            tries: self.tries,
+            connect_tries: self.connect_tries,
+        }
+    }
+
+    /// Records a successful take.
+    fn checked_out(&self) {
+        let progress = self.progress();
+        self.pool.inner.metrics.waited(progress.elapsed);
+        self.pool.inner.events.on_checkout(progress);
+    }
+
+    pub(crate) fn phase(&self) -> Phase {
+        match self.state {
+            State::Acquire | State::Waiting(_) | State::Full(..) => Phase::Waiting,
+            State::Creating(_) => Phase::Connecting,
+            State::Checkout(..) | State::Testing(_) => Phase::Testing,
+            State::Backoff(_) => Phase::Backoff,
+        }
+    }
+
+    /// Tells a factory call in flight that the take gave up on it, e.g. once its deadline fired.
+    pub(crate) fn timed_out(&mut self) {
+        if let State::Creating(ref mut factory_future) = self.state {
+            factory_future.timed_out();
+        }
+    }
+}
+
+impl<T> Drop for PoolTaker<T>
+where
+    T: PoolObject + Send + 'static,
 {
-    fn backoff_timeout(&mut self) -> Option<Duration> {
-        match self.backoff {
-            BackoffStrategy::Exponential(ref mut bo) => bo.next(),
-            BackoffStrategy::Fibonacci(ref mut bo) => bo.next(),
-            BackoffStrategy::Fixed(ref mut bo) => bo.next(),
-            BackoffStrategy::None => None,
+    fn drop(&mut self) {
+        self.pool.inner.takers.lock().remove(&self.id);
+        match mem::replace(&mut self.state, State::Acquire) {
+            State::Creating(_) => {
+                //the object will never be created, give its slot back
+                self.pool.release();
+            }
+            State::Waiting(mut waiter) => {
+                //something might have been handed to us right before we gave up
+                waiter.close();
+                match waiter.try_recv() {
+                    Ok(Some(Handoff::Object(object))) => self.pool.put_idle(object),
+                    Ok(Some(Handoff::Slot)) => self.pool.release(),
+                    _ => {}
+                }
+            }
+            State::Full(shared, mut waiter) => {
+                waiter.close();
+                match waiter.try_recv() {
+                    Ok(Some(Handoff::Object(object))) => self.pool.put_idle(object),
+                    Ok(Some(Handoff::Slot)) => shared.release(),
+                    _ => {}
+                }
+            }
+            _ => {}
         }
     }
 }
 
 impl<T> Future for PoolTaker<T>
 where
-    T: PoolObject,
+    T: PoolObject + Send + 'static,
 {
-    type Output = Result<PoolGuard<T>>;
+    type Output = Result<PoolGuard<T>, Error<T::Error>>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        if !self.first_poll {
-            if self.pool.timeout.is_some() && self.started_at.elapsed() > self.pool.timeout.unwrap()
-            {
-                return Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
+        //registered before the pool is checked, so that a close can't slip in between
+        {
+            let mut takers = self.pool.inner.takers.lock();
+            let registered = takers
+                .get(&self.id)
+                .map(|waker| waker.will_wake(cx.waker()))
+                .unwrap_or(false);
+            if !registered {
+                takers.insert(self.id, cx.waker().clone());
             }
         }
 
-        self.backoff_delay = None;
-        self.first_poll = false;
-
-        let available_object = self.pool.try_take();
-        let object_in_progress = self.factory_future_in_progress.is_some();
-        if available_object.is_none() && !object_in_progress {
-            debug!("get object from connector");
-            //1. get a connection from the pool connector
-
-            self.factory_future_in_progress = Some((self.pool.factory)());
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        } else if let Some(ref mut factory_future) = self.factory_future_in_progress {
-            debug!("poll object");
-            //2. the connection we got from the connector is trying to connect
-            //once it does, we put it in the pool
-
-            let object = try_ready!(factory_future.as_mut().poll(cx));
-            self.pool.put(object);
-            self.factory_future_in_progress = None;
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        } else if let Some(mut object) = available_object {
-            debug!("use a ready object");
-            //3. we have a connected connection, sometimes it's a brand new one
-            //sometimes it's recycled. We need to test whether it's usable
-
-            match object.test_poll(cx) {
-                Poll::Ready(Ok(usable)) => {
-                    debug!("object test_poll, usable={}", usable);
-                    if usable {
-                        Poll::Ready(Ok(object))
-                    } else {
-                        object.detach(); //dispose of the object
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
+        loop {
+            if self.pool.is_closed() {
+                debug!("pool closed while taking an object");
+                return Poll::Ready(Err(Error::Closed));
+            }
+
+            //the deadline is polled on every pass so it wakes us even if the phase never does
+            if let Some(ref mut deadline) = self.deadline {
+                if deadline.poll_unpin(cx).is_ready() {
+                    self.timed_out();
+                    let phase = self.phase();
+                    debug!("timed out after {:?} while {}", self.elapsed(), phase);
+                    self.pool.inner.metrics.acquire_timeout();
+                    self.pool.inner.events.on_acquire_timeout(phase, self.progress());
+                    return Poll::Ready(Err(Error::Timeout { phase }));
+                }
+            }
+
+            let state = mem::replace(&mut self.state, State::Acquire);
+            self.state = match state {
+                State::Acquire => match self.pool.acquire() {
+                    Acquire::Object(object, idle_for) => State::Checkout(object, Some(idle_for)),
+                    Acquire::Reserved => {
+                        debug!("get object from connector");
+                        //1. get a connection from the pool connector
+                        match self.pool.create() {
+                            Some(factory_future) => State::Creating(factory_future),
+                            None => return Poll::Ready(Err(Error::CircuitOpen)),
+                        }
+                    }
+                    Acquire::Waiting(waiter) => {
+                        debug!("max_size reached, wait for an object to be returned");
+                        State::Waiting(waiter)
+                    }
+                    Acquire::Full(shared) => {
+                        let waiter = shared.wait(&self.pool);
+                        //idle objects which went idle before we got in line make room now,
+                        //later ones are evicted as they are returned
+                        if shared.evict_idle(&self.pool) {
+                            debug!("evicted an idle object of another partition");
+                        }
+                        State::Full(shared, waiter)
+                    }
+                    Acquire::Closed => return Poll::Ready(Err(Error::Closed)),
+                },
+
+                State::Full(shared, mut waiter) => match waiter.poll_unpin(cx) {
+                    Poll::Ready(Ok(Handoff::Object(object))) => {
+                        State::Checkout(PoolGuard::new(object, self.pool.clone()), None)
+                    }
+                    Poll::Ready(Ok(Handoff::Slot)) => {
+                        if self.pool.try_reserve_own() {
+                            match self.pool.create() {
+                                Some(factory_future) => State::Creating(factory_future),
+                                None => return Poll::Ready(Err(Error::CircuitOpen)),
+                            }
+                        } else {
+                            //our partition filled up meanwhile, wait for one of its objects
+                            shared.release();
+                            State::Acquire
+                        }
+                    }
+                    Poll::Ready(_) => State::Acquire,
+                    Poll::Pending => {
+                        self.state = State::Full(shared, waiter);
+                        return Poll::Pending;
+                    }
+                },
+
+                State::Waiting(mut waiter) => match waiter.poll_unpin(cx) {
+                    Poll::Ready(Ok(Handoff::Object(object))) => {
+                        State::Checkout(PoolGuard::new(object, self.pool.clone()), None)
+                    }
+                    Poll::Ready(Ok(Handoff::Slot)) => match self.pool.create() {
+                        Some(factory_future) => State::Creating(factory_future),
+                        None => return Poll::Ready(Err(Error::CircuitOpen)),
+                    },
+                    Poll::Ready(Err(_)) => State::Acquire,
+                    Poll::Pending => {
+                        self.state = State::Waiting(waiter);
+                        return Poll::Pending;
+                    }
+                },
+
+                State::Creating(mut factory_future) => {
+                    debug!("poll object");
+                    //2. the connection we got from the connector is trying to connect
+                    //once it does, we test it right away
+
+                    match factory_future.poll_unpin(cx) {
+                        Poll::Ready(Ok(object)) => {
+                            let entry = self.pool.new_entry(object);
+                            State::Checkout(PoolGuard::new(entry, self.pool.clone()), None)
+                        }
+                        Poll::Ready(Err(err)) => {
+                            debug!("factory failed, err={}", &err);
+                            self.pool.release();
+                            self.connect_tries += 1;
+                            if self.pool.inner.connect_max_tries.is_some()
+                                && self.connect_tries >= self.pool.inner.connect_max_tries.unwrap()
+                            {
+                                debug!("factory reached max tries {}", &err);
+                                return Poll::Ready(Err(Error::MaxTriesExceeded {
+                                    last_error: Box::new(Error::Factory(err)),
+                                    tries: self.connect_tries,
+                                }));
+                            }
+
+                            let delay = self.connect_backoff.next_delay();
+                            ready!(self.retry(delay, cx))
+                        }
+                        Poll::Pending => {
+                            self.state = State::Creating(factory_future);
+                            return Poll::Pending;
+                        }
                     }
                 }
 
-                Poll::Ready(Err(err)) => {
-                    debug!("object test_poll, err={}", &err);
-                    self.tries = self.tries + 1;
-                    if self.pool.max_tries.is_some() && self.tries >= self.pool.max_tries.unwrap() {
-                        debug!("object reached max tries {}", &err);
-                        Poll::Ready(Err(err))
+                State::Checkout(mut object, idle_for) => {
+                    debug!("use a ready object");
+                    //3. we have a connected connection, sometimes it's a brand new one
+                    //sometimes it's recycled. We need to test whether it's usable
+
+                    if object.is_expired() {
+                        debug!("object outlived max_lifetime");
+                        object.discard();
+                        State::Acquire
+                    } else if self.pool.inner.health_check.on_checkout(idle_for) {
+                        State::Testing(object)
                     } else {
-                        let timeout = self.backoff_timeout();
-                        debug!("object timeout {:?}", &timeout);
-                        if let Some(timeout) = timeout {
-                            self.backoff_delay = Some(Delay::new(timeout));
-                            match self.backoff_delay {
-                                Some(ref mut delay) => ready!(delay.poll_unpin(cx))?,
-                                None => unreachable!(),
-                            };
+                        self.checked_out();
+                        return Poll::Ready(Ok(object));
+                    }
+                }
+
+                State::Testing(mut object) => {
+                    let res = match object.test_poll(cx) {
+                        Poll::Ready(res) => {
+                            self.pool.health_checked(&res);
+                            Poll::Ready(res)
+                        }
+                        Poll::Pending => Poll::Pending,
+                    };
+
+                    match res {
+                        Poll::Ready(Ok(usable)) => {
+                            debug!("object test_poll, usable={}", usable);
+                            if usable {
+                                self.checked_out();
+                                return Poll::Ready(Ok(object));
+                            }
+
+                            object.discard();
+                            ready!(self.retry(None, cx))
                         }
 
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
+                        Poll::Ready(Err(err)) => {
+                            debug!("object test_poll, err={}", &err);
+                            object.discard();
+                            self.tries += 1;
+                            if self.pool.inner.max_tries.is_some()
+                                && self.tries >= self.pool.inner.max_tries.unwrap()
+                            {
+                                debug!("object reached max tries {}", &err);
+                                return Poll::Ready(Err(Error::MaxTriesExceeded {
+                                    last_error: Box::new(Error::Validation(err)),
+                                    tries: self.tries,
+                                }));
+                            }
+
+                            let delay = self.backoff.next_delay();
+                            ready!(self.retry(delay, cx))
+                        }
+
+                        Poll::Pending => {
+                            debug!("object test_poll pending");
+                            self.state = State::Testing(object);
+                            return Poll::Pending;
+                        }
                     }
                 }
-                Poll::Pending => {
-                    debug!("object test_poll pending");
-                    Poll::Pending
-                }
-            }
-        } else {
-            unreachable!("the pool is in an invalid state")
+
+                State::Backoff(mut delay) => match delay.poll_unpin(cx) {
+                    Poll::Ready(()) => State::Acquire,
+                    Poll::Pending => {
+                        self.state = State::Backoff(delay);
+                        return Poll::Pending;
+                    }
+                },
+            };
         }
     }
 }
@@ -0,0 +1,309 @@
+//! Deterministic helpers for testing code which uses a pool, enabled with the `test-util` feature.
+//!
+//! `MockRuntime` only moves its clock when `advance` is called, so timeouts, backoff,
+//! idle eviction and lifetime expiry can be stepped through without sleeping. Futures are
+//! driven one step at a time with `poll_once`, and `MockFactory` scripts what every factory
+//! call does.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Context;
+use std::time::{Duration, Instant};
+
+use futures::task::{noop_waker_ref, Waker};
+use futures::{Future, FutureExt, Poll};
+use parking_lot::Mutex;
+
+use crate::object::PoolObject;
+use crate::runtime::{Runtime, RuntimeFuture};
+
+/// Polls `future` once with a waker which does nothing.
+pub fn poll_once<F>(future: &mut F) -> Poll<F::Output>
+where
+    F: Future + Unpin,
+{
+    let mut cx = Context::from_waker(noop_waker_ref());
+    future.poll_unpin(&mut cx)
+}
+
+/// A `Runtime` with a virtual clock.
+///
+/// Sleeps complete once `advance` moves the clock past their deadline. Spawned futures
+/// are queued and polled by `run_spawned`, which `advance` calls as well.
+#[derive(Clone)]
+pub struct MockRuntime {
+    inner: Arc<Mutex<MockClock>>,
+}
+
+struct MockClock {
+    now: Instant,
+    timers: Vec<(Instant, Arc<MockTimer>)>,
+    tasks: Vec<RuntimeFuture>,
+}
+
+#[derive(Default)]
+struct MockTimer {
+    fired: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl MockTimer {
+    fn fire(&self) {
+        self.fired.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+struct MockSleep {
+    timer: Arc<MockTimer>,
+}
+
+impl Future for MockSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.timer.fired.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+
+        *self.timer.waker.lock() = Some(cx.waker().clone());
+        //it might have fired while the waker was stored
+        if self.timer.fired.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl MockRuntime {
+    pub fn new() -> MockRuntime {
+        MockRuntime {
+            inner: Arc::new(Mutex::new(MockClock {
+                now: Instant::now(),
+                timers: Vec::new(),
+                tasks: Vec::new(),
+            })),
+        }
+    }
+
+    /// Moves the clock forward, completes the sleeps which are due and runs the spawned futures.
+    pub fn advance(&self, duration: Duration) {
+        let due = {
+            let mut clock = self.inner.lock();
+            clock.now += duration;
+            let now = clock.now;
+            let (due, sleeping) = clock
+                .timers
+                .drain(..)
+                .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+            clock.timers = sleeping;
+            due
+        };
+
+        due.into_iter().for_each(|(_, timer)| timer.fire());
+        self.run_spawned();
+    }
+
+    /// Polls every spawned future once, returns how many are still pending.
+    pub fn run_spawned(&self) -> usize {
+        let tasks = {
+            let mut clock = self.inner.lock();
+            clock.tasks.drain(..).collect::<Vec<_>>()
+        };
+
+        //the lock isn't held, so the tasks may spawn more tasks
+        let mut pending: Vec<RuntimeFuture> = tasks
+            .into_iter()
+            .filter_map(|mut task| match poll_once(&mut task) {
+                Poll::Ready(()) => None,
+                Poll::Pending => Some(task),
+            })
+            .collect();
+
+        let mut clock = self.inner.lock();
+        pending.append(&mut clock.tasks);
+        clock.tasks = pending;
+        clock.tasks.len()
+    }
+
+    /// How many sleeps are waiting for the clock to reach their deadline.
+    pub fn sleeping(&self) -> usize {
+        let mut clock = self.inner.lock();
+        //forget the sleeps which were dropped before they were due
+        clock.timers.retain(|(_, timer)| Arc::strong_count(timer) > 1);
+        clock.timers.len()
+    }
+}
+
+impl Default for MockRuntime {
+    fn default() -> MockRuntime {
+        MockRuntime::new()
+    }
+}
+
+impl Runtime for MockRuntime {
+    fn now(&self) -> Instant {
+        self.inner.lock().now
+    }
+
+    fn sleep(&self, duration: Duration) -> RuntimeFuture {
+        let timer = Arc::new(MockTimer::default());
+        if duration == Duration::from_secs(0) {
+            timer.fired.store(true, Ordering::SeqCst);
+        } else {
+            let mut clock = self.inner.lock();
+            let deadline = clock.now + duration;
+            clock.timers.push((deadline, timer.clone()));
+        }
+
+        Box::pin(MockSleep { timer })
+    }
+
+    fn spawn(&self, future: RuntimeFuture) {
+        self.inner.lock().tasks.push(future);
+    }
+}
+
+/// The error of `MockFactory` and `MockObject`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockError(pub String);
+
+impl fmt::Display for MockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for MockError {}
+
+/// A pooled object whose `test_poll` result is controlled by the test.
+/// Clones share their state, so a handle can be kept while the pool owns the object.
+#[derive(Debug, Clone)]
+pub struct MockObject {
+    id: usize,
+    health: Arc<MockHealth>,
+}
+
+#[derive(Debug)]
+struct MockHealth {
+    usable: AtomicBool,
+    error: Mutex<Option<MockError>>,
+    tests: AtomicUsize,
+}
+
+impl MockObject {
+    pub fn new(id: usize) -> MockObject {
+        MockObject {
+            id,
+            health: Arc::new(MockHealth {
+                usable: AtomicBool::new(true),
+                error: Mutex::new(None),
+                tests: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// What `test_poll` returns when it doesn't fail.
+    pub fn set_usable(&self, usable: bool) {
+        self.health.usable.store(usable, Ordering::SeqCst);
+    }
+
+    /// Makes `test_poll` fail with `error`, `None` makes it succeed again.
+    pub fn set_error(&self, error: Option<MockError>) {
+        *self.health.error.lock() = error;
+    }
+
+    /// How many times `test_poll` was called.
+    pub fn tests(&self) -> usize {
+        self.health.tests.load(Ordering::SeqCst)
+    }
+}
+
+impl PoolObject for MockObject {
+    type Error = MockError;
+
+    fn test_poll(&mut self, _: &mut Context) -> Poll<Result<bool, MockError>> {
+        self.health.tests.fetch_add(1, Ordering::SeqCst);
+        match *self.health.error.lock() {
+            Some(ref err) => Poll::Ready(Err(err.clone())),
+            None => Poll::Ready(Ok(self.health.usable.load(Ordering::SeqCst))),
+        }
+    }
+}
+
+/// What a `MockFactory::create` call does, `MockCreate::Object` once the script runs out.
+#[derive(Debug, Clone)]
+pub enum MockCreate {
+    /// Creates a healthy object.
+    Object,
+    /// Creates an object whose `test_poll` returns `Ok(false)`.
+    Unusable,
+    /// Creates an object whose `test_poll` fails.
+    FailingTest(MockError),
+    /// The factory fails.
+    Fail(MockError),
+    /// The factory never completes.
+    Hang,
+}
+
+/// A factory of `MockObject`s following a script, clones share the script.
+/// Pass it to the pool as `.factory(move || factory.create())`.
+#[derive(Clone, Default)]
+pub struct MockFactory {
+    inner: Arc<Mutex<MockFactoryState>>,
+}
+
+#[derive(Default)]
+struct MockFactoryState {
+    calls: usize,
+    script: VecDeque<MockCreate>,
+    created: Vec<MockObject>,
+}
+
+impl MockFactory {
+    pub fn new() -> MockFactory {
+        MockFactory::default()
+    }
+
+    /// Queues what the next `create` call does.
+    pub fn script(&self, create: MockCreate) -> &Self {
+        self.inner.lock().script.push_back(create);
+        self
+    }
+
+    pub fn create(&self) -> Pin<Box<dyn Future<Output = Result<MockObject, MockError>> + Send>> {
+        let mut state = self.inner.lock();
+        state.calls += 1;
+        let object = MockObject::new(state.calls);
+        match state.script.pop_front().unwrap_or(MockCreate::Object) {
+            MockCreate::Object => {}
+            MockCreate::Unusable => object.set_usable(false),
+            MockCreate::FailingTest(err) => object.set_error(Some(err)),
+            MockCreate::Fail(err) => return Box::pin(futures::future::err(err)),
+            MockCreate::Hang => return Box::pin(futures::future::pending()),
+        }
+
+        state.created.push(object.clone());
+        Box::pin(futures::future::ok(object))
+    }
+
+    /// How many times `create` was called.
+    pub fn calls(&self) -> usize {
+        self.inner.lock().calls
+    }
+
+    /// Handles to every object created so far, oldest first.
+    pub fn created(&self) -> Vec<MockObject> {
+        self.inner.lock().created.clone()
+    }
+}
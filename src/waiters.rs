@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+
+use futures::channel::oneshot::{self, Receiver, Sender};
+
+/// What a waiting take receives from its pool.
+pub(crate) enum Handoff<O> {
+    /// An idle object which was returned to the pool.
+    Object(O),
+    /// A slot freed by a discarded object, the taker may create a new one.
+    Slot,
+}
+
+/// The takes waiting for an object or a free slot, shared by `Pool` and `LocalPool`.
+///
+/// Every returned object or freed slot goes to a single take, oldest first, so a release
+/// wakes exactly one of them. Takes which gave up dropped their receiver and are skipped.
+///
+/// Every take is queued along with a key `K`, e.g. the partition of a `KeyedPool` it takes
+/// from, so that objects which only serve some of them go to the oldest take with that key.
+pub(crate) struct Waiters<O, K = ()> {
+    queue: VecDeque<(K, Sender<Handoff<O>>)>,
+}
+
+impl<O> Waiters<O> {
+    /// Queues a take behind the ones already waiting.
+    pub(crate) fn push(&mut self) -> Receiver<Handoff<O>> {
+        self.push_keyed(())
+    }
+
+    /// Hands `object` to the oldest take which still waits, gives it back if there is none.
+    pub(crate) fn hand_object(&mut self, object: O) -> Result<(), O> {
+        self.hand_object_keyed(&(), object)
+    }
+}
+
+impl<O, K> Waiters<O, K>
+where
+    K: PartialEq,
+{
+    pub(crate) fn new() -> Waiters<O, K> {
+        Waiters {
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Queues a take with `key` behind the ones already waiting.
+    pub(crate) fn push_keyed(&mut self, key: K) -> Receiver<Handoff<O>> {
+        let (sender, receiver) = oneshot::channel();
+        self.queue.push_back((key, sender));
+        receiver
+    }
+
+    /// Hands `object` to the oldest take with `key` which still waits,
+    /// gives it back if there is none. Takes with other keys keep their place.
+    pub(crate) fn hand_object_keyed(&mut self, key: &K, mut object: O) -> Result<(), O> {
+        while let Some(pos) = self.queue.iter().position(|(waiter_key, _)| waiter_key == key) {
+            let (_, waiter) = self.queue.remove(pos).expect("waiter position is in the queue");
+            match waiter.send(Handoff::Object(object)) {
+                Ok(()) => return Ok(()),
+                Err(Handoff::Object(returned)) => object = returned,
+                Err(Handoff::Slot) => unreachable!(),
+            }
+        }
+
+        Err(object)
+    }
+
+    /// Hands a freed slot to the oldest take which still waits, false if there is none.
+    pub(crate) fn hand_slot(&mut self) -> bool {
+        while let Some((_, waiter)) = self.queue.pop_front() {
+            if waiter.send(Handoff::Slot).is_ok() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// How many takes still wait.
+    pub(crate) fn len(&self) -> usize {
+        self.queue
+            .iter()
+            .filter(|(_, waiter)| !waiter.is_canceled())
+            .count()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every waiting take's sender, their receivers resolve with `Canceled`.
+    pub(crate) fn clear(&mut self) {
+        self.queue.clear();
+    }
+}